@@ -2,8 +2,10 @@ use std::ops::Add;
 
 use serde::{Deserialize, Serialize};
 use teloxide::types::ParseMode;
+use teloxide::utils::html;
 use teloxide::{prelude::*, RequestError};
-use userdb::db::{RaffleDB, Partecipant};
+use userdb::db::{Partecipant, DrawMode, Raffle, RaffleID, ScoringRules, UserID};
+use userdb::draw::to_hex;
 use super::{dialogues::*, RaffleBot};
 use crate::commands::Context;
 use crate::utils::*;
@@ -38,22 +40,19 @@ impl RaffleDescription {
 
 
 
-pub async fn stats(ctx: Context)
+pub async fn stats(selection: RaffleSelection, ctx: Context)
     -> TransitionOut<Dialogue> {
-        let user = match ctx.update.from() {
-            Some(u) => u.id,
-            None => { 
-                return next(Dialogue::Begin(NoData));
-            }
-        };
-        if !is_admin(user) {
-            ctx.answer("You must be an admin to run this command.").await?;
+        if admin_only(&ctx).await?.is_none() {
             return next(Dialogue::Begin(NoData));
         }
 
+        let raffle = match choose_raffle_or_explain(selection, "/stats", &ctx, "stats: choose raffle").await? {
+            Some(raffle) => raffle,
+            None => return next(Dialogue::Begin(NoData))
+        };
         let partecipants = {
             let raffle_db = crate::DB_INSTANCE.lock().await;
-            raffle_db.get_partecipants()
+            raffle_db.get_partecipants(raffle.raffle_id)
         };
         let mut partecipants = match partecipants {
             Ok(partecipants) => Vec::from_iter(partecipants.iter().map(|p| p.clone())),
@@ -74,10 +73,30 @@ pub async fn stats(ctx: Context)
                 Err(_) => format!("user id {}, ask crax", part.user_id)
             };
             let place =  i + 1;
-            msg = msg.add(format!("{}. {} - {} point(s)", place, tag, part.priority).add("\n").as_str());
+            msg = msg.add(format!("{}. {} (id {}) - {} point(s)", place, tag, part.user_id, part.priority).add("\n").as_str());
         }
 
-        let msg = format!("<b>Top ten:</b>\n{}\n\n<b>Raffle stats:</b>\nNumber of partecipants: {}", msg, count_partecipants);
+        let msg = format!("<b>{}</b>\n\n<b>Top ten:</b>\n{}\n\n<b>Raffle stats:</b>\nNumber of partecipants: {}", html::escape(&raffle.raffle_name), msg, count_partecipants);
+        let campaigns = {
+            let raffle_db = crate::DB_INSTANCE.lock().await;
+            raffle_db.get_campaign_joins(raffle.raffle_id)
+        };
+        let msg = match campaigns {
+            // Only worth showing once some campaign link has been used
+            Ok(campaigns) if campaigns.iter().any(|joins| joins.campaign.is_some()) => {
+                let campaigns = campaigns.iter()
+                    .map(|joins| format!("{} - {} join(s), {} still in the raffle",
+                        joins.campaign.as_deref().map(html::escape).unwrap_or_else(|| "no campaign".to_owned()), joins.joins, joins.partecipants))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                format!("{}\n\n<b>Joins by campaign:</b>\n{}", msg, campaigns)
+            },
+            Ok(_) => msg,
+            Err(e) => {
+                on_error(e, &ctx.update, &ctx.requester, "stats: fetch campaigns").await;
+                return next(Dialogue::Begin(NoData));
+            }
+        };
         ctx.answer(msg)
             .parse_mode(ParseMode::Html)
             .await?;
@@ -87,30 +106,7 @@ pub async fn stats(ctx: Context)
 
 pub async fn create_raffle(ctx: Context)
     -> TransitionOut<Dialogue> {
-    let user = match ctx.update.from() {
-        Some(u) => u.id,
-        None => { 
-            return next(Dialogue::Begin(NoData));
-        }
-    };
-    if !is_admin(user) {
-        ctx.answer("You must be an admin to run this command.").await?;
-        return next(Dialogue::Begin(NoData));
-    }
-    
-    let creation_status = {
-        let raffle_db = crate::DB_INSTANCE.lock().await;
-        raffle_db.get_ongoing_raffle()
-    };
-    let creation_status = match creation_status {
-        Err(e) => {
-            on_error(e, &ctx.update, &ctx.requester, "on raffle creation: begin").await;
-            return next(Dialogue::Begin(NoData));
-        },
-        Ok(status) => status
-    };
-    if creation_status.is_some() {
-        ctx.answer("There is a raffle ongoing already, please end it before starting a new one.").await?;
+    if admin_only(&ctx).await?.is_none() {
         return next(Dialogue::Begin(NoData));
     }
     ctx.answer("Sure! Send me the title of the raffle.").await?;
@@ -147,72 +143,286 @@ async fn raffle_get_desc_message(
 ) -> TransitionOut<Dialogue> {
     let message_serialized = serde_json::to_string(&RaffleDescription::from_message(&cx.update))
         .expect("Failure in serializing the message from the user");
+    cx.answer(format!("Got it! Now tell me how the winners should be picked:
+{} - each point is a ticket, winners are drawn at random
+{} - the partecipants with the most points win", WEIGHTED, LEADERBOARD)).await?;
+    next(Dialogue::AwaitingRaffleDrawMode(AwaitingRaffleDrawModeState {
+        title: state.title,
+        description: message_serialized
+    }))
+}
+
+const WEIGHTED: &str = "weighted";
+const LEADERBOARD: &str = "leaderboard";
+
+fn parse_draw_mode(answer: &str) -> Option<DrawMode> {
+    match answer.trim().to_lowercase().as_str() {
+        WEIGHTED => Some(DrawMode::Weighted),
+        LEADERBOARD => Some(DrawMode::Leaderboard),
+        _ => None
+    }
+}
+
+#[teloxide(subtransition)]
+async fn raffle_get_draw_mode(
+    state: AwaitingRaffleDrawModeState,
+    cx: TransitionIn<RaffleBot>,
+    _ans: String
+) -> TransitionOut<Dialogue> {
+    let draw_mode = match cx.update.text().and_then(parse_draw_mode) {
+        Some(draw_mode) => draw_mode,
+        None => {
+            cx.answer(format!("Please answer either {} or {}", WEIGHTED, LEADERBOARD)).await?;
+            return next(state);
+        }
+    };
+    cx.answer(format!("Last thing: how many points should partecipants get for joining, for each person they invite and for each code they redeem?
+Send me the three numbers, e.g. 0 3 5, or {} to give one point for each.", DEFAULT_SCORING)).await?;
+    next(Dialogue::AwaitingRaffleScoring(AwaitingRaffleScoringState {
+        title: state.title,
+        description: state.description,
+        draw_mode
+    }))
+}
+
+const DEFAULT_SCORING: &str = "default";
+
+// Three numbers, the points for joining, for each referral and for each code
+fn parse_scoring_rules(answer: &str) -> Option<ScoringRules> {
+    if answer.trim().eq_ignore_ascii_case(DEFAULT_SCORING) {
+        return Some(ScoringRules::default());
+    }
+    let points = answer.split_whitespace()
+        .map(|points| points.parse::<i64>().ok().filter(|points| *points >= 0))
+        .collect::<Option<Vec<_>>>()?;
+    match points.as_slice() {
+        [join_points, referral_points, code_points] => Some(ScoringRules {
+            join_points: *join_points,
+            referral_points: *referral_points,
+            code_points: *code_points
+        }),
+        _ => None
+    }
+}
+
+fn describe_scoring_rules(rules: &ScoringRules) -> String {
+    format!("{} for joining, {} for each person invited and {} for each code redeemed",
+        points_text(rules.join_points), points_text(rules.referral_points), points_text(rules.code_points))
+}
+
+#[teloxide(subtransition)]
+async fn raffle_get_scoring(
+    state: AwaitingRaffleScoringState,
+    cx: TransitionIn<RaffleBot>,
+    _ans: String
+) -> TransitionOut<Dialogue> {
+    let scoring_rules = match cx.update.text().and_then(parse_scoring_rules) {
+        Some(rules) => rules,
+        None => {
+            cx.answer(format!("Please send three numbers that are 0 or more, e.g. 1 1 1, or {}", DEFAULT_SCORING)).await?;
+            return next(state);
+        }
+    };
     let creation_status = {
         let mut raffle_db = crate::DB_INSTANCE.lock().await;
-        match raffle_db.create_raffle(state.title.as_str(), message_serialized.as_str()) {
+        match raffle_db.create_raffle(state.title.as_str(), state.description.as_str(), state.draw_mode, scoring_rules) {
             Ok(status) => status,
             Err(e) => {
-                on_error(e, &cx.update, &cx.requester, "on raffle: await raffle desc").await;
+                on_error(e, &cx.update, &cx.requester, "on raffle: await raffle scoring").await;
                 return next(Dialogue::Begin(NoData));
             }
         }
     };
 
     match creation_status {
-        userdb::db::RaffleCreationResult::Success(_) => {
+        userdb::db::RaffleCreationResult::Success(raffle) => {
             cx.answer("Success! A new raffle was started!").await?;
+            if let Some(hash) = raffle.seed_hash {
+                let commitment = format!("A new raffle, <b>{}</b>, has started!
+Partecipants get {}.
+
+The winners will be drawn using a secret seed, this is its SHA-256 hash:
+<code>{}</code>
+The seed will be revealed when the raffle ends, so that anyone can check the draw.", html::escape(&raffle.raffle_name), describe_scoring_rules(&raffle.scoring_rules), hash);
+                cx.answer(commitment.as_str())
+                    .parse_mode(ParseMode::Html)
+                    .await?;
+                // Publish the commitment to the community too, before anyone can join
+                let _ = cx.requester.send_message(target_chat(), commitment)
+                    .parse_mode(ParseMode::Html)
+                    .await;
+            }
         },
         userdb::db::RaffleCreationResult::OngoingRaffleExists(_) => {
-            cx.answer("There is a new raffle already, maybe someone else created it before you?").await?;
+            cx.answer("There is an ongoing raffle with the same title already, maybe someone else created it before you?").await?;
         }
     };
     exit()
 }
 
-async fn send_winner_notification(place: usize, winner: &Partecipant, bot: &RaffleBot) -> Result<(), RequestError> {
-    let msg = format!("Congraulations! You placed {} in the current raffle, with a toal of {} points, contact the raffle manager for your prize.",
+async fn send_winner_notification(place: usize, winner: &Partecipant, raffle: &Raffle, bot: &RaffleBot) -> Result<(), RequestError> {
+    let msg = format!("Congraulations! You placed {} in the {} raffle, with a toal of {} points, contact the raffle manager for your prize.",
     place,
+    raffle.raffle_name,
     winner.priority);
     bot.send_message(winner.user_id, msg).await?;
     Ok(())
 }
 
-pub async fn end_raffle(ctx: Context)
+pub async fn end_raffle(selection: RaffleSelection, ctx: Context)
     -> TransitionOut<Dialogue> {
-        let user = match ctx.update.from() {
-            Some(u) => u.id,
-            None => { 
-                return next(Dialogue::Begin(NoData));
-            }
-        };
-        if !is_admin(user) {
-            ctx.answer("You must be an admin to run this command.").await?;
+        if admin_only(&ctx).await?.is_none() {
             return next(Dialogue::Begin(NoData));
         }
         
+        let raffle = match choose_raffle_or_explain(selection, "/endraffle", &ctx, "on raffle end: choose raffle").await? {
+            Some(raffle) => raffle,
+            None => return next(Dialogue::Begin(NoData))
+        };
         const WINNER_COUNT : usize = 1;
-        let winners = {
+        let outcome = {
             let mut raffle_db = crate::DB_INSTANCE.lock().await;
-            raffle_db.stop_raffle(WINNER_COUNT)
+            raffle_db.stop_raffle(raffle.raffle_id, WINNER_COUNT)
         };
-        let winners = match winners {
+        let outcome = match outcome {
             Err(e) => {
                 on_error(e, &ctx.update, &ctx.requester, "on raffle end").await;
                 return next(Dialogue::Begin(NoData));
             },
-            Ok(vector) => vector
+            Ok(outcome) => outcome
         };
         let mut winner_str = String::new();
-        for (i, winner) in winners.iter().enumerate() {
+        for (i, winner) in outcome.winners.iter().enumerate() {
             let tag = match get_user_tag(winner.user_id, target_chat(), &ctx.requester).await {
                 Ok(n) => n,
                 Err(_) => format!("user id {}, ask crax", winner.user_id)
             };
             let place =  i + 1;
             winner_str = winner_str.add(format!("{}. {} - {} point(s)", place, tag, winner.priority).add("\n").as_str());
-            let _ = send_winner_notification(place, &winner, &ctx.requester).await; // Best to ignore the error
+            let _ = send_winner_notification(place, &winner, &raffle, &ctx.requester).await; // Best to ignore the error
         }
         ctx.answer(format!("Okay! Here are the winners i picked for this raffle:\n{}", winner_str)).await?;
+        let reveal = format!("The raffle has ended, here is how the winners were drawn.
+
+Secret seed:
+<code>{}</code>
+SHA-256 of the sorted partecipant:points list:
+<code>{}</code>
+
+Hashing the seed gives the hash published when the raffle started, and drawing with it gives the same winners.", to_hex(&outcome.seed), outcome.partecipants_hash);
+        ctx.answer(reveal.as_str())
+            .parse_mode(ParseMode::Html)
+            .await?;
+        let _ = ctx.requester.send_message(target_chat(), reveal)
+            .parse_mode(ParseMode::Html)
+            .await;
         next(Dialogue::AwaitRaffleTitle(AwaitingRaffleTitleState))
 
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Copy)]
+pub enum PointsAdjustment {
+    Grant,
+    Deduct
+}
+
+impl PointsAdjustment {
+    fn command(self) -> &'static str {
+        match self {
+            PointsAdjustment::Grant => "/grant",
+            PointsAdjustment::Deduct => "/deduct",
+        }
+    }
+}
+
+struct AdjustmentArgs {
+    user_id: UserID,
+    points: i64,
+    selection: RaffleSelection,
+    reason: String
+}
+
+const RAFFLE_ARG: &str = "raffle=";
+
+// The arguments are <user id> <points> [raffle=N] <reason>, the error is what to tell the admin
+fn parse_adjustment_args(args: &str, command: &str) -> Result<AdjustmentArgs, String> {
+    let usage = format!("Usage: {} USER_ID POINTS [{}N] REASON", command, RAFFLE_ARG);
+    let mut parts = args.trim().splitn(3, char::is_whitespace);
+    let user_id = match parts.next().and_then(|user| user.parse::<UserID>().ok()) {
+        Some(user_id) => user_id,
+        None => return Err(format!("Sorry, i couldn't parse the user id, you can find it with /stats.\n{}", usage))
+    };
+    let points = match parts.next().and_then(|points| points.parse::<i64>().ok()) {
+        Some(points) if points > 0 => points,
+        _ => return Err(format!("Sorry, the points must be a positive number.\n{}", usage))
+    };
+    let rest = parts.next().unwrap_or_default().trim();
+    let (first, after_first) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let (selection, reason) = match first.strip_prefix(RAFFLE_ARG) {
+        Some(raffle) => match raffle.parse::<RaffleID>() {
+            Ok(raffle) => (RaffleSelection(Some(raffle)), after_first.trim()),
+            Err(_) => return Err(format!("Sorry, i couldn't parse the raffle number.\n{}", usage))
+        },
+        None => (RaffleSelection(None), rest)
+    };
+    if reason.is_empty() {
+        return Err(format!("Sorry, you must say why.\n{}", usage));
+    }
+    // A number first may be meant as the raffle, better ask than guess
+    if let Some(number) = reason.split_whitespace().next().filter(|word| word.parse::<RaffleID>().is_ok()) {
+        return Err(format!("Sorry, i can't tell whether {} is a raffle or part of the reason: type {}{} to pick that raffle, or start the reason with a word.\n{}",
+            number, RAFFLE_ARG, number, usage));
+    }
+    Ok(AdjustmentArgs {
+        user_id,
+        points,
+        selection,
+        reason: reason.to_owned()
+    })
+}
+
+pub async fn adjust_points_cmd(args: String, adjustment: PointsAdjustment, ctx: Context)
+    -> TransitionOut<Dialogue> {
+        let admin = match admin_only(&ctx).await? {
+            Some(admin) => admin,
+            None => return next(Dialogue::Begin(NoData))
+        };
+        let args = match parse_adjustment_args(&args, adjustment.command()) {
+            Ok(args) => args,
+            Err(explanation) => {
+                ctx.answer(explanation).await?;
+                return next(Dialogue::Begin(NoData));
+            }
+        };
+        let raffle = match choose_raffle_or_explain(args.selection, &format!("{} {} {} {}", adjustment.command(), args.user_id, args.points, RAFFLE_ARG), &ctx, "on points adjustment: choose raffle").await? {
+            Some(raffle) => raffle,
+            None => return next(Dialogue::Begin(NoData))
+        };
+        let points = match adjustment {
+            PointsAdjustment::Grant => args.points,
+            PointsAdjustment::Deduct => -args.points,
+        };
+        let adjusted = {
+            let mut raffle_db = crate::DB_INSTANCE.lock().await;
+            raffle_db.adjust_points(raffle.raffle_id, args.user_id, points, admin, &args.reason)
+        };
+        let partecipant = match adjusted {
+            Ok(Some(partecipant)) => partecipant,
+            Ok(None) => {
+                ctx.answer(format!("Sorry, the user {} is not a partecipant of {}.", args.user_id, raffle.raffle_name)).await?;
+                return next(Dialogue::Begin(NoData));
+            }
+            Err(e) => {
+                on_error(e, &ctx.update, &ctx.requester, "on points adjustment").await;
+                return next(Dialogue::Begin(NoData));
+            }
+        };
+        ctx.answer(format!("Done! The user {} now has {} points in {}.", args.user_id, partecipant.priority, raffle.raffle_name)).await?;
+        let notice = match adjustment {
+            PointsAdjustment::Grant => format!("An admin gave you {} points in the {} raffle: {}", args.points, raffle.raffle_name, args.reason),
+            PointsAdjustment::Deduct => format!("An admin took {} points from you in the {} raffle: {}", args.points, raffle.raffle_name, args.reason),
+        };
+        let notice = format!("{}\nYou now have {} points, see them with /points.", notice, partecipant.priority);
+        let _ = ctx.requester.send_message(args.user_id, notice).await; // The user may have blocked the bot
+        next(Dialogue::Begin(NoData))
+}
@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, ParseMode};
+use teloxide::utils::html;
+use userdb::db::{CodeUseCount, RedeemableCode, UserID};
+use userdb::db_instances::{MAX_CAMPAIGN_LENGTH, normalize_campaign};
+use crate::commands::Context;
+use crate::utils::*;
+
+use super::dialogues::*;
+use super::start::{StartData, make_code_link, make_start_link};
+
+const CODES_PER_PAGE: usize = 20;
+// Telegram messages are limited in length, so only the latest redeemals of a code are listed
+const REDEEMALS_LISTED: usize = 30;
+
+// What can still be done with a code, e.g. "3 uses left"
+fn code_status(code: &RedeemableCode) -> String {
+    if let Some(revoked_when) = code.revoked_when {
+        return format!("revoked on {}", format_timestamp(revoked_when));
+    }
+    match code.use_count(timestamp_now()) {
+        CodeUseCount::Expired => format!("expired on {}", code.expires_when.map(format_timestamp).unwrap_or_default()),
+        CodeUseCount::Illimited => "illimited uses".to_owned(),
+        CodeUseCount::Counted(0) => "no uses left".to_owned(),
+        CodeUseCount::Counted(1) | CodeUseCount::Once => "1 use left".to_owned(),
+        CodeUseCount::Counted(uses) => format!("{} uses left", uses),
+        CodeUseCount::CodeNotValid => "not valid".to_owned()
+    }
+}
+
+pub async fn list_codes_cmd(
+    args: String,
+    ctx: Context) -> TransitionOut<Dialogue> {
+    if admin_only(&ctx).await?.is_none() {
+        return next(Dialogue::Begin(NoData));
+    }
+
+    // The arguments are [page] [raffle number]
+    let mut args = args.split_whitespace();
+    let page = match args.next().map(|page| page.parse::<usize>()) {
+        None => 1,
+        Some(Ok(page)) if page > 0 => page,
+        Some(_) => {
+            ctx.answer("Sorry, but i couldn't parse the page number.").await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let selection = match args.next().unwrap_or_default().parse::<RaffleSelection>() {
+        Ok(selection) => selection,
+        Err(_) => {
+            ctx.answer("Sorry, but i couldn't parse the raffle number.").await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let raffle = match choose_raffle_or_explain(selection, &format!("/codes {}", page), &ctx, "on codes list: choose raffle").await? {
+        Some(raffle) => raffle,
+        None => return next(Dialogue::Begin(NoData))
+    };
+    let codes = {
+        let raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.get_raffle_codes(raffle.raffle_id)
+            .and_then(|codes| Ok((codes, raffle_db.get_used_codes(raffle.raffle_id)?)))
+    };
+    let (codes, used_codes) = match codes {
+        Ok(codes) => codes,
+        Err(e) => {
+            on_error(e, &ctx.update, &ctx.requester, "on codes list").await;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let mut codes = Vec::from_iter(codes);
+    codes.sort_by_key(|code| code.unique_id);
+    let mut times_used = HashMap::new();
+    for used in used_codes.iter() {
+        *times_used.entry(used.code.as_str()).or_insert(0) += 1;
+    }
+
+    if codes.is_empty() {
+        ctx.answer(format!("There are no codes in {} yet, create one with /generatecode.", raffle.raffle_name)).await?;
+        return next(Dialogue::Begin(NoData));
+    }
+    let pages = (codes.len() + CODES_PER_PAGE - 1) / CODES_PER_PAGE;
+    if page > pages {
+        ctx.answer(format!("Sorry, there are only {} pages of codes.", pages)).await?;
+        return next(Dialogue::Begin(NoData));
+    }
+    let lines = codes.iter()
+        .skip((page - 1) * CODES_PER_PAGE)
+        .take(CODES_PER_PAGE)
+        .map(|code| {
+            let label = code.label.as_ref().map(|label| format!(" ({})", html::escape(label))).unwrap_or_default();
+            format!("<code>{}</code>{} - {}, used {} times",
+                code.code, label, code_status(code), times_used.get(code.code.as_str()).copied().unwrap_or(0))
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let more = if page < pages {
+        format!("\n\nType /codes {} {} for the next page.", page + 1, raffle.raffle_id)
+    } else {
+        String::new()
+    };
+    ctx.answer(format!("<b>Codes of {}</b>, page {} of {}:\n{}{}", html::escape(&raffle.raffle_name), page, pages, lines, more))
+        .parse_mode(ParseMode::Html)
+        .await?;
+    next(Dialogue::Begin(NoData))
+}
+
+pub async fn code_details_cmd(
+    code_string: String,
+    ctx: Context) -> TransitionOut<Dialogue> {
+    if admin_only(&ctx).await?.is_none() {
+        return next(Dialogue::Begin(NoData));
+    }
+    let details = {
+        let raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.find_raffle_code(&code_string).and_then(|code| match code {
+            Some(code) => {
+                let raffle = raffle_db.get_raffle(code.raffle_id)?;
+                let owners = raffle_db.get_code_owners(code.unique_id)?;
+                let redeemals = raffle_db.get_used_codes(code.raffle_id)?
+                    .into_iter()
+                    .filter(|used| used.code == code.code)
+                    .collect::<Vec<_>>();
+                Ok(Some((code, raffle, owners, redeemals)))
+            },
+            None => Ok(None)
+        })
+    };
+    let (code, raffle, owners, redeemals) = match details {
+        Ok(Some(details)) => details,
+        Ok(None) => {
+            ctx.answer("Sorry, there is no such code.").await?;
+            return next(Dialogue::Begin(NoData));
+        }
+        Err(e) => {
+            on_error(e, &ctx.update, &ctx.requester, "on code details").await;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+
+    let raffle_name = raffle.as_ref().map(|raffle| raffle.raffle_name.clone()).unwrap_or_default();
+    let worth = match (code.points, raffle.as_ref()) {
+        (Some(points), _) => points_text(points),
+        (None, Some(raffle)) => format!("{}, as any code of the raffle", points_text(raffle.scoring_rules.code_points)),
+        (None, None) => "the raffle's code points".to_owned()
+    };
+    let mut msg = format!("<b>Code <code>{}</code></b>
+Raffle: {} (number {})
+Worth: {}
+Status: {}
+Generated on {}",
+        code.code, html::escape(&raffle_name), code.raffle_id, worth, code_status(&code), format_timestamp(code.generated_when));
+    if let Some(label) = code.label.as_ref() {
+        msg.push_str(&format!("\nLabel: {}", html::escape(label)));
+    }
+    if !owners.is_empty() {
+        msg.push_str(&format!("\nPersonal, only for {}", owners_text(&owners)));
+    }
+    let me = ctx.requester.get_me().await?.user.username.unwrap_or_default();
+    if let Some(link) = make_code_link(&me, &code.code) {
+        msg.push_str(&format!("\nLink: {}", link));
+    }
+    if redeemals.is_empty() {
+        msg.push_str("\n\nNobody redeemed it yet.");
+    } else {
+        msg.push_str(&format!("\n\n<b>Redeemed {} times:</b>", redeemals.len()));
+        let skipped = redeemals.len().saturating_sub(REDEEMALS_LISTED);
+        for used in redeemals.iter().skip(skipped) {
+            let tag = match get_user_tag(used.partecpiant_user_id, target_chat(), &ctx.requester).await {
+                Ok(tag) => tag,
+                Err(_) => format!("user id {}", used.partecpiant_user_id)
+            };
+            msg.push_str(&format!("\n{} on {}", tag, format_timestamp(used.used_when)));
+        }
+        if skipped > 0 {
+            msg.push_str(&format!("\n... and {} more who redeemed it earlier", skipped));
+        }
+    }
+    ctx.answer(msg)
+        .parse_mode(ParseMode::Html)
+        .await?;
+    next(Dialogue::Begin(NoData))
+}
+
+// What /revokecode did with the code it was given
+enum Revocation {
+    Revoked(RedeemableCode),
+    RaffleEnded(RedeemableCode),
+    AlreadyUnusable(RedeemableCode) // Used up, expired or revoked before
+}
+
+pub async fn revoke_code_cmd(
+    code_string: String,
+    ctx: Context) -> TransitionOut<Dialogue> {
+    if admin_only(&ctx).await?.is_none() {
+        return next(Dialogue::Begin(NoData));
+    }
+    let revoked = {
+        let mut raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.find_raffle_code(&code_string).and_then(|code| match code {
+            Some(code) => {
+                let raffle_ended = raffle_db.get_raffle(code.raffle_id)?.is_none_or(|raffle| raffle.ended_when.is_some());
+                // Only a code that could still be redeemed is revoked
+                Ok(Some(if raffle_ended {
+                    Revocation::RaffleEnded(code)
+                } else if matches!(code.use_count(timestamp_now()), CodeUseCount::Expired | CodeUseCount::Counted(0)) {
+                    Revocation::AlreadyUnusable(code)
+                } else {
+                    raffle_db.delete_raffle_code(code.unique_id)?;
+                    Revocation::Revoked(code)
+                }))
+            },
+            None => Ok(None)
+        })
+    };
+    match revoked {
+        Ok(Some(Revocation::Revoked(code))) => {
+            ctx.answer(format!("Done! The code {} can't be redeemed anymore, the points already given are kept.", code.code)).await?;
+        },
+        Ok(Some(Revocation::RaffleEnded(code))) => {
+            ctx.answer(format!("The code {} can't be redeemed already, its raffle has ended.", code.code)).await?;
+        },
+        Ok(Some(Revocation::AlreadyUnusable(code))) => {
+            ctx.answer(format!("The code {} can't be redeemed already: {}.", code.code, code_status(&code))).await?;
+        },
+        Ok(None) => {
+            ctx.answer("Sorry, there is no such code.").await?;
+        },
+        Err(e) => {
+            on_error(e, &ctx.update, &ctx.requester, "on code revocation").await;
+        }
+    }
+    next(Dialogue::Begin(NoData))
+}
+
+const QR_CODE: &str = "code";
+const QR_REFERRAL: &str = "ref";
+const QR_RAFFLE: &str = "raffle";
+const QR_CAMPAIGN: &str = "campaign=";
+
+/*
+The arguments are code CODE, ref USER_ID [raffle number] or raffle [raffle number],
+optionally followed by campaign=TAG to count in /stats the joins that came from the link
+*/
+pub async fn qr_cmd(
+    args: String,
+    ctx: Context) -> TransitionOut<Dialogue> {
+    if admin_only(&ctx).await?.is_none() {
+        return next(Dialogue::Begin(NoData));
+    }
+    let usage = format!("Please type /qr {} CODE for a code, /qr {} USER_ID [raffle number] for someone's referral link or /qr {} [raffle number] to just join a raffle, followed by {}TAG to track where the link is shared.",
+        QR_CODE, QR_REFERRAL, QR_RAFFLE, QR_CAMPAIGN);
+    let (campaign_args, args): (Vec<&str>, Vec<&str>) = args.split_whitespace().partition(|arg| arg.starts_with(QR_CAMPAIGN));
+    let campaign = match campaign_args.as_slice() {
+        [] => None,
+        [tag] => match normalize_campaign(&tag[QR_CAMPAIGN.len()..]) {
+            Some(campaign) => Some(campaign),
+            None => {
+                ctx.answer(format!("Sorry, a campaign tag is made of up to {} letters, digits and _, e.g. poster_milan.", MAX_CAMPAIGN_LENGTH)).await?;
+                return next(Dialogue::Begin(NoData));
+            }
+        },
+        _ => {
+            ctx.answer(usage).await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let (data, raffle_args) = match args.as_slice() {
+        [QR_CODE, code_string] => {
+            let code = {
+                let raffle_db = crate::DB_INSTANCE.lock().await;
+                raffle_db.find_raffle_code(code_string)
+            };
+            match code {
+                Ok(Some(code)) => (StartData { code: Some(code.code), ..StartData::default() }, None),
+                Ok(None) => {
+                    ctx.answer("Sorry, there is no such code.").await?;
+                    return next(Dialogue::Begin(NoData));
+                }
+                Err(e) => {
+                    on_error(e, &ctx.update, &ctx.requester, "on qr code: find code").await;
+                    return next(Dialogue::Begin(NoData));
+                }
+            }
+        },
+        [QR_REFERRAL, referrer, selection @ ..] if selection.len() <= 1 => match referrer.parse::<UserID>() {
+            Ok(referrer) => (StartData { referrer: Some(referrer), ..StartData::default() },
+                Some((selection.first().copied().unwrap_or_default(), format!("/qr {} {}", QR_REFERRAL, referrer)))),
+            Err(_) => {
+                ctx.answer(usage).await?;
+                return next(Dialogue::Begin(NoData));
+            }
+        },
+        [QR_RAFFLE, selection @ ..] if selection.len() <= 1 =>
+            (StartData::default(), Some((selection.first().copied().unwrap_or_default(), format!("/qr {}", QR_RAFFLE)))),
+        _ => {
+            ctx.answer(usage).await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    // Referral and raffle links need an ongoing raffle, code links join the raffle of the code
+    let raffle = match raffle_args.map(|(selection, command)| (selection.parse::<RaffleSelection>(), command)) {
+        None => None,
+        Some((Ok(selection), command)) => match choose_raffle_or_explain(selection, &command, &ctx, "on qr code: choose raffle").await? {
+            Some(raffle) => Some(raffle.raffle_id),
+            None => return next(Dialogue::Begin(NoData))
+        },
+        Some((Err(_), _)) => {
+            ctx.answer(usage).await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    // A referral token already stands for its raffle
+    let data = match (data.referrer, raffle) {
+        (Some(referrer), Some(raffle)) => {
+            let token = {
+                let mut raffle_db = crate::DB_INSTANCE.lock().await;
+                raffle_db.get_referral_token(raffle, referrer)
+            };
+            match token {
+                Ok(Some(token)) => StartData { referral_token: Some(token.token), campaign, ..StartData::default() },
+                Ok(None) => {
+                    ctx.answer(format!("Sorry, user {} is not a partecipant of this raffle.", referrer)).await?;
+                    return next(Dialogue::Begin(NoData));
+                }
+                Err(e) => {
+                    on_error(e, &ctx.update, &ctx.requester, "on qr code: referral token").await;
+                    return next(Dialogue::Begin(NoData));
+                }
+            }
+        },
+        _ => StartData { raffle, campaign, ..data }
+    };
+    let me = ctx.requester.get_me().await?.user.username.unwrap_or_default();
+    let link = match make_start_link(&me, &data) {
+        Some(link) => link,
+        None => {
+            ctx.answer("Sorry, this doesn't fit in a link, try a shorter campaign tag or, for an old code, generate a new one.").await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    match qr_code_png(&link) {
+        Ok(png) => {
+            ctx.answer_photo(InputFile::memory("qr.png", png))
+                .caption(link)
+                .await?;
+        },
+        Err(e) => {
+            log::error!("While rendering the qr code of {}: {}", link, e);
+            ctx.answer("Sorry, i couldn't render the qr code.").await?;
+        }
+    }
+    next(Dialogue::Begin(NoData))
+}
@@ -3,7 +3,7 @@ use teloxide::{
     prelude::*,
     macros::Transition
 };
-use userdb::db::UserID;
+use userdb::db::{DrawMode, UserID, RaffleID};
 use crate::{utils::*, commands::RaffleBot};
 use crate::commands::start::*;
 
@@ -11,9 +11,12 @@ use crate::commands::start::*;
 pub enum Dialogue {
     Begin(NoData),
     AwaitingJoinChannel(AwaitingJoinChannelState),
+    AwaitingJoinRaffle(AwaitingJoinRaffleState),
     Registered(RegistrationState),
     AwaitRaffleTitle(AwaitingRaffleTitleState),
     AwaitingRaffleMessage(AwaitingRaffleMessageState),
+    AwaitingRaffleDrawMode(AwaitingRaffleDrawModeState),
+    AwaitingRaffleScoring(AwaitingRaffleScoringState),
     AwaitingLeaveAnswer(LeaveState)
 }
 
@@ -32,9 +35,40 @@ pub struct AwaitingRaffleMessageState {
     pub title: String
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct AwaitingRaffleDrawModeState {
+    pub title: String,
+    pub description: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AwaitingRaffleScoringState {
+    pub title: String,
+    pub description: String,
+    #[serde(with = "DrawModeDef")]
+    pub draw_mode: DrawMode
+}
+
+// DrawMode comes from userdb, which doesn't depend on serde
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "DrawMode")]
+enum DrawModeDef {
+    Weighted,
+    Leaderboard
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AwaitingJoinChannelState {
-    pub referrer: Option<UserID>
+    pub raffle: Option<RaffleID>,
+    pub referrer: Option<UserID>,
+    pub campaign: Option<String>
+}
+
+// A link that doesn't tell which raffle to join, kept until the user picks one
+#[derive(Serialize, Deserialize)]
+pub struct AwaitingJoinRaffleState {
+    pub referrer: Option<UserID>,
+    pub campaign: Option<String>
 }
 
 impl Default for Dialogue {
@@ -77,4 +111,19 @@ async fn await_join_channel_msg(
         cx.answer("All right! Welcome to the raffle!").await?;
         next(Dialogue::Registered(RegistrationState))
     }
-}
\ No newline at end of file
+}
+
+#[teloxide(subtransition)]
+async fn await_join_raffle_msg(
+    state: AwaitingJoinRaffleState,
+    cx: TransitionIn<RaffleBot>,
+    ans: String
+) -> TransitionOut<Dialogue> {
+    match ans.trim().parse::<RaffleID>() {
+        Ok(raffle) => join_cmd(RaffleSelection(Some(raffle)), state.referrer, state.campaign, cx).await,
+        Err(_) => {
+            cx.answer("Please type /join followed by the number of the raffle you want to join.").await?;
+            next(state)
+        }
+    }
+}
@@ -2,13 +2,18 @@ mod start;
 mod redeem;
 mod points;
 mod admin;
+mod codes;
+mod referrals;
 mod dialogues;
 
 use start::*;
 use admin::*;
+use codes::*;
 use redeem::*;
 use points::*;
+use referrals::*;
 use teloxide::{prelude::*, utils::command::BotCommand, adaptors::CacheMe};
+use crate::utils::RaffleSelection;
 
 pub type RaffleBot = AutoSend<CacheMe<Bot>>;
 pub type Context = UpdateWithCx<RaffleBot, Message>;
@@ -24,26 +29,61 @@ pub enum Command {
     #[command()]
     Start(StartData),
     StartRaffle,
-    EndRaffle,
-    Stats,
-    Join(StartData),
-    Leave,
+    EndRaffle(RaffleSelection),
+    Stats(RaffleSelection),
+    Join(RaffleSelection),
+    Leave(RaffleSelection),
+    #[command(parse_with = "default")] // Codes may be typed with spaces
     Redeem(String),
+    #[command(parse_with = "default")]
     GenerateCode(String),
+    #[command(parse_with = "default")]
+    BulkCodes(String),
+    #[command(parse_with = "default")]
+    Codes(String),
+    #[command(parse_with = "default")]
+    Code(String),
+    #[command(parse_with = "default")]
+    RevokeCode(String),
+    #[command(parse_with = "default")]
+    Qr(String),
     Points,
+    #[command(parse_with = "default")]
+    MyRef(String),
+    #[command(parse_with = "default")]
+    Notifications(String),
+    MyReferrals,
+    #[command(parse_with = "default")]
+    Grant(String),
+    #[command(parse_with = "default")]
+    Deduct(String),
 }
 
-pub async fn handle_action(ctx: Context, command: Command) -> TransitionOut<Dialogue> {
+pub async fn handle_action(ctx: Context, command: Command, dialogue: Dialogue) -> TransitionOut<Dialogue> {
     match command {
-        Command::Start(data) => start_cmd(data.referrer, ctx).await,
-        Command::GenerateCode(uses) => generate_code_cmd(uses, ctx).await,
-        Command::Join(data) => join_cmd(data.referrer, ctx).await,
-        Command::Leave => leave_cmd(ctx).await,
+        Command::Start(data) => start_cmd(data, ctx).await,
+        Command::GenerateCode(args) => generate_code_cmd(args, ctx).await,
+        Command::BulkCodes(args) => bulk_codes_cmd(args, ctx).await,
+        Command::Codes(args) => list_codes_cmd(args, ctx).await,
+        Command::Code(code) => code_details_cmd(code, ctx).await,
+        Command::RevokeCode(code) => revoke_code_cmd(code, ctx).await,
+        Command::Qr(args) => qr_cmd(args, ctx).await,
+        // /join keeps the referrer of a link that was valid in more than one raffle
+        Command::Join(selection) => match dialogue {
+            Dialogue::AwaitingJoinRaffle(state) => join_cmd(selection, state.referrer, state.campaign, ctx).await,
+            _ => join_cmd(selection, None, None, ctx).await
+        },
+        Command::Leave(selection) => leave_cmd(selection, ctx).await,
         Command::Redeem(data) => redeem_code_cmd(data, ctx).await,
         Command::Points => get_points_cdm(ctx).await,
-        Command::Stats => stats(ctx).await,
+        Command::MyRef(slug) => my_ref_cmd(slug, ctx).await,
+        Command::Notifications(arg) => notifications_cmd(arg, ctx).await,
+        Command::MyReferrals => my_referrals_cmd(ctx).await,
+        Command::Stats(selection) => stats(selection, ctx).await,
 
         Command::StartRaffle => create_raffle(ctx).await,
-        Command::EndRaffle => end_raffle(ctx).await
+        Command::EndRaffle(selection) => end_raffle(selection, ctx).await,
+        Command::Grant(args) => adjust_points_cmd(args, PointsAdjustment::Grant, ctx).await,
+        Command::Deduct(args) => adjust_points_cmd(args, PointsAdjustment::Deduct, ctx).await
     }
 }
\ No newline at end of file
@@ -1,5 +1,7 @@
 use teloxide::prelude::*;
-use userdb::db::RaffleDB;
+use teloxide::types::ParseMode;
+use teloxide::utils::html;
+use userdb::db::{PointEvent, PointSource};
 use crate::commands::Context;
 use crate::utils::*;
 
@@ -19,26 +21,80 @@ pub async fn get_points_cdm(
         .await?;
         return next(Dialogue::Begin(NoData));
     }
-    let partecipant = {
+    let raffle_points = {
         let raffle_db = crate::DB_INSTANCE.lock().await;
-        raffle_db.get_partecipant(user_id)
-    };
-    let partecipant = match partecipant {
-        Ok(part_maybe) => match part_maybe {
-            Some(part) => part,
-            None => {
-                ctx.answer("Sorry, you must be a member of the raffle in order to get points.")
-                .await?;
-                return next(Dialogue::Begin(NoData));
+        raffle_db.get_ongoing_raffles().and_then(|raffles| {
+            let mut raffle_points = vec![];
+            for raffle in raffles {
+                if let Some(partecipant) = raffle_db.get_partecipant(raffle.raffle_id, user_id)? {
+                    let events = raffle_db.get_point_events(raffle.raffle_id, user_id)?;
+                    raffle_points.push((raffle, partecipant, events));
+                }
             }
-        },
+            Ok(raffle_points)
+        })
+    };
+    let raffle_points = match raffle_points {
+        Ok(raffle_points) => raffle_points,
         Err(e) => {
             on_error(e, &ctx.update, &ctx.requester, "on points").await;
             return next(Dialogue::Begin(NoData));
         }
     };
 
-    ctx.answer(format!("Sure! You have {} points.", partecipant.priority))
-    .await?;
+    match raffle_points.as_slice() {
+        [] => {
+            ctx.answer("Sorry, you must be a member of the raffle in order to get points.")
+            .await?;
+        }
+        [(_, partecipant, events)] => {
+            ctx.answer(format!("Sure! You have {} points:\n{}", partecipant.priority, points_breakdown(events)))
+            .parse_mode(ParseMode::Html)
+            .await?;
+        }
+        _ => {
+            let points = raffle_points.iter()
+                .map(|(raffle, partecipant, events)| format!("<b>{}</b>: {} points\n{}",
+                    html::escape(&raffle.raffle_name), partecipant.priority, points_breakdown(events)))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            ctx.answer(format!("Sure! Here are your points in each raffle:\n{}", points))
+            .parse_mode(ParseMode::Html)
+            .await?;
+        }
+    }
     next(Dialogue::Begin(NoData))
-}
\ No newline at end of file
+}
+
+// One line for each kind of point, adjustments are listed one by one since each has its own reason
+fn points_breakdown(events: &[PointEvent]) -> String {
+    let (mut join, mut referrals, mut referral_points, mut codes, mut code_points) = (0, 0, 0, 0, 0);
+    let mut adjustments = vec![];
+    for event in events {
+        match &event.source {
+            PointSource::Join => join += event.points,
+            PointSource::Referral(_) => {
+                referrals += 1;
+                referral_points += event.points;
+            },
+            PointSource::Code(_) => {
+                codes += 1;
+                code_points += event.points;
+            },
+            PointSource::Adjustment { reason, .. } =>
+                adjustments.push(format!("- {:+} given by an admin: {}", event.points, html::escape(reason))),
+        }
+    }
+    let mut lines = vec![];
+    if join != 0 {
+        lines.push(format!("- {} for joining", join));
+    }
+    if referrals > 0 {
+        lines.push(format!("- {} for inviting {} people", referral_points, referrals));
+    }
+    if codes > 0 {
+        lines.push(format!("- {} for redeeming {} codes", code_points, codes));
+    }
+    lines.extend(adjustments);
+    lines.join("\n")
+}
@@ -1,43 +1,64 @@
 use teloxide::prelude::*;
-use userdb::db::{RaffleDB, CodeUseCount};
+use teloxide::types::InputFile;
+use userdb::db::{CodeOptions, CodeUseCount, Raffle, RedeemableCode, UserID};
+use userdb::db_instances::MAX_CODES_PER_BATCH;
 use crate::commands::Context;
 use crate::utils::*;
 
 use super::dialogues::*;
+use super::start::make_code_link;
+
 pub async fn generate_code_cmd(
-    usage_string: String,
+    args: String,
     ctx: Context) -> TransitionOut<Dialogue> {
-    let from_user = ctx.update.from();
-    if from_user.is_none() {
-        return exit();
-    }
-    let user_id = from_user.unwrap().id;
-    if !is_admin(user_id) {
-        // show admin keyboard
-        ctx.answer("This command can only be used by an admin.")
-        .await?;
+    if admin_only(&ctx).await?.is_none() {
         return next(Dialogue::Begin(NoData));
     }
 
-    let usage = match usage_string.to_lowercase().as_str(){
-        "illimited" => CodeUseCount::Illimited,
-        "once" | "1" | "" => CodeUseCount::Once,
-        num_string => {
-            let n = num_string.parse::<i32>();
-            if n.is_err() {
-                ctx.answer("Sorry, but i couldn't parse the usage argument as a number.")
-                .await?;
-                return next(Dialogue::Begin(NoData)); 
-            }
-            let n = n.unwrap();
-            CodeUseCount::Counted(n)
+    let CodeArgs { usage_string, selection, options } = match parse_code_args(&args) {
+        Ok(code_args) => code_args,
+        Err(problem) => {
+            ctx.answer(problem)
+            .await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let usage_string = usage_string.as_str();
+    let usage = match parse_usage(usage_string) {
+        Some(usage) => usage,
+        None => {
+            ctx.answer("Sorry, but i couldn't parse the usage argument as a number.")
+            .await?;
+            return next(Dialogue::Begin(NoData));
         }
     };
+    let usage_hint = if usage_string.is_empty() { "once" } else { usage_string };
+    let raffle = match choose_raffle_or_explain(selection, &format!("/generatecode {}", usage_hint), &ctx, "on raffle code creation: choose raffle").await? {
+        Some(raffle) => raffle,
+        None => return next(Dialogue::Begin(NoData))
+    };
     let mut raffle_db = crate::DB_INSTANCE.lock().await;
-    match raffle_db.generate_raffle_code(usage) {
+    let owners = options.owners.clone();
+    match raffle_db.generate_raffle_code(raffle.raffle_id, usage, options) {
         Ok(code) => {
-            ctx.answer(format!("Ok, i generated a code which can be used {} times.\nThe code is:", code.remaining_uses)).await?;
+            let worth = match code.points {
+                Some(points) => points_text(points),
+                None => format!("{}, as any code of this raffle", points_text(raffle.scoring_rules.code_points))
+            };
+            let label = code.label.as_ref().map(|label| format!(" for {}", label)).unwrap_or_default();
+            let expiry = code.expires_when.map(|expires_when| format!(" until {}", format_timestamp(expires_when))).unwrap_or_default();
+            let personal = if owners.is_empty() {
+                String::new()
+            } else {
+                format!("\nOnly {} can redeem it.", owners_text(&owners))
+            };
+            ctx.answer(format!("Ok, i generated a code{} which can be used {} times{}, it is worth {}.{}\nThe code is:", label, code.remaining_uses, expiry, worth, personal)).await?;
+            let me = ctx.requester.get_me().await?.user.username.unwrap_or_default();
+            let link = make_code_link(&me, &code.code);
             ctx.answer(code.code).await?;
+            if let Some(link) = link {
+                ctx.answer(format!("Tapping this link joins the raffle and redeems the code: {}\nUse /qr to print it as a QR code.", link)).await?;
+            }
         },
         Err(e) => {
             on_error(e, &ctx.update, &ctx.requester, "on raffle code reation").await;
@@ -45,6 +66,185 @@ pub async fn generate_code_cmd(
     }
     next(Dialogue::Begin(NoData))
 }
+
+pub async fn bulk_codes_cmd(
+    args: String,
+    ctx: Context) -> TransitionOut<Dialogue> {
+    if admin_only(&ctx).await?.is_none() {
+        return next(Dialogue::Begin(NoData));
+    }
+
+    // The arguments are COUNT followed by the same ones as /generatecode
+    let (count_string, args) = args.trim_start().split_once(char::is_whitespace).unwrap_or((args.trim(), ""));
+    let count = match count_string.parse::<usize>() {
+        Ok(count) if count > 0 && count <= MAX_CODES_PER_BATCH => count,
+        _ => {
+            ctx.answer(format!("Please tell me how many codes you need, from 1 to {}, e.g. /bulkcodes 100 once", MAX_CODES_PER_BATCH))
+            .await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let CodeArgs { usage_string, selection, options } = match parse_code_args(args) {
+        Ok(code_args) => code_args,
+        Err(problem) => {
+            ctx.answer(problem)
+            .await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let usage = match parse_usage(&usage_string) {
+        Some(usage) => usage,
+        None => {
+            ctx.answer("Sorry, but i couldn't parse the usage argument as a number.")
+            .await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let usage_hint = if usage_string.is_empty() { "once" } else { usage_string.as_str() };
+    let raffle = match choose_raffle_or_explain(selection, &format!("/bulkcodes {} {}", count, usage_hint), &ctx, "on bulk code creation: choose raffle").await? {
+        Some(raffle) => raffle,
+        None => return next(Dialogue::Begin(NoData))
+    };
+    let codes = {
+        let mut raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.generate_raffle_codes(raffle.raffle_id, count, usage, options)
+    };
+    match codes {
+        Ok(codes) => {
+            let csv = codes_csv(&codes, &raffle);
+            ctx.answer_document(InputFile::memory(format!("codes-raffle-{}.csv", raffle.raffle_id), csv.into_bytes()))
+                .caption(format!("Here are {} new codes for {}", codes.len(), raffle.raffle_name))
+                .await?;
+        },
+        Err(e) => {
+            on_error(e, &ctx.update, &ctx.requester, "on bulk code creation").await;
+        }
+    }
+    next(Dialogue::Begin(NoData))
+}
+
+fn parse_usage(usage_string: &str) -> Option<CodeUseCount> {
+    Some(match usage_string.to_lowercase().as_str() {
+        "illimited" => CodeUseCount::Illimited,
+        "once" | "1" | "" => CodeUseCount::Once,
+        num_string => CodeUseCount::Counted(num_string.parse::<i32>().ok()?)
+    })
+}
+
+// One line for each code, ready to be merged into flyers and badges
+fn codes_csv(codes: &[RedeemableCode], raffle: &Raffle) -> String {
+    let mut csv = String::from("code,points,max_uses,expires,label\n");
+    for code in codes {
+        let max_uses = match code.remaining_uses {
+            -1 => "illimited".to_owned(),
+            uses => uses.to_string()
+        };
+        let line = [
+            code.code.clone(),
+            code.points.unwrap_or(raffle.scoring_rules.code_points).to_string(),
+            max_uses,
+            code.expires_when.map(format_timestamp).unwrap_or_default(),
+            code.label.clone().unwrap_or_default()
+        ];
+        csv.push_str(&line.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+const POINTS_ARG: &str = "points=";
+const VALID_ARG: &str = "valid=";
+const UNTIL_ARG: &str = "until=";
+const FOR_ARG: &str = "for=";
+const LABEL_ARG: &str = "label=";
+
+struct CodeArgs {
+    usage_string: String,
+    selection: RaffleSelection,
+    options: CodeOptions
+}
+
+/*
+The arguments are [usages] [raffle number] followed by any of
+points=N, valid=DURATION (e.g. 30m, 2h, 7d), until=YYYY-MM-DD [HH:MM] in UTC,
+for=USER_ID,USER_ID... to make a personal code and label=the rest of the line, which must come last.
+The error is what to tell the admin.
+*/
+fn parse_code_args(args: &str) -> Result<CodeArgs, String> {
+    // The label starts at the first word beginning with label=, not wherever label= appears
+    let label_start = args.match_indices(LABEL_ARG)
+        .map(|(start, _)| start)
+        .find(|start| args[..*start].chars().next_back().is_none_or(char::is_whitespace));
+    let (args, label) = match label_start {
+        Some(start) => (&args[..start], Some(args[start + LABEL_ARG.len()..].trim().to_owned())),
+        None => (args, None)
+    };
+    let mut options = CodeOptions { label, ..CodeOptions::default() };
+    let mut positional = vec![];
+    let mut args = args.split_whitespace().peekable();
+    while let Some(arg) = args.next() {
+        if let Some(points) = arg.strip_prefix(POINTS_ARG) {
+            options.points = Some(points.parse::<i64>().ok()
+                .filter(|points| *points >= 0)
+                .ok_or("Sorry, but the points of a code must be a number, 0 or more.")?);
+        } else if let Some(duration) = arg.strip_prefix(VALID_ARG) {
+            let seconds = parse_duration(duration)
+                .ok_or("Sorry, but i couldn't understand how long the code is valid, use e.g. valid=30m, valid=2h or valid=7d.")?;
+            options.expires_when = Some(timestamp_now().saturating_add(seconds));
+        } else if let Some(owners) = arg.strip_prefix(FOR_ARG) {
+            options.owners = owners.split(',')
+                .map(|owner| owner.parse::<UserID>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| "Sorry, but i couldn't parse the users of the code, use their ids, e.g. for=12345,67890.")?;
+        } else if let Some(date) = arg.strip_prefix(UNTIL_ARG) {
+            // The time is a separate word, and it is optional
+            let time = args.next_if(|next| next.contains(':'));
+            options.expires_when = Some(parse_date(date, time)
+                .ok_or("Sorry, but i couldn't understand the expiry date, use e.g. until=2026-12-01 18:00 (UTC).")?);
+        } else {
+            positional.push(arg);
+        }
+    }
+    positional.resize(2, "");
+    let selection = positional[1].parse::<RaffleSelection>()
+        .map_err(|_| "Sorry, but i couldn't parse the raffle number.")?;
+    Ok(CodeArgs {
+        usage_string: positional[0].to_owned(),
+        selection,
+        options
+    })
+}
+
+// A number followed by m, h or d, in seconds
+fn parse_duration(duration: &str) -> Option<u64> {
+    let unit = match duration.chars().last()? {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None
+    };
+    let amount = duration[..duration.len() - 1].parse::<u64>().ok().filter(|amount| *amount > 0)?;
+    amount.checked_mul(unit)
+}
+
+// A day without a time lasts until its end
+fn parse_date(date: &str, time: Option<&str>) -> Option<u64> {
+    let date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    let moment = match time {
+        Some(time) => date.and_time(chrono::NaiveTime::parse_from_str(time, "%H:%M").ok()?),
+        None => date.succ_opt()?.and_hms(0, 0, 0)
+    };
+    u64::try_from(moment.timestamp()).ok()
+}
+
 pub async fn redeem_code_cmd(
     code_string: String,
     cx: Context) -> TransitionOut<Dialogue> {
@@ -61,27 +261,33 @@ pub async fn redeem_code_cmd(
     }
     let code = {
         let raffle_db = crate::DB_INSTANCE.lock().await;
-        raffle_db.get_raffle_code_by_name(code_string.as_str())
+        // Used up codes are found too, so that the partecipant is told why they can't have it
+        raffle_db.find_raffle_code(code_string.as_str())
+    };
+    let code = match code {
+        Ok(code) => code,
+        Err(e) => {
+            on_error(e, &cx.update, &cx.requester, "on code redeemal: find code").await;
+            return next(Dialogue::Begin(NoData));
+        }
     };
-    if code.is_err() {
-        cx.answer("Sorry, a fatal error happened, please report this to my maintainer").await?;
-        return next(Dialogue::Begin(NoData));
-    }
-    let code = code.unwrap();
     match code {
         Some(code_id) => {
             let result = {
                 let mut raffle_db = crate::DB_INSTANCE.lock().await;
                 raffle_db.redeem_code(user_id, code_id.unique_id)
             };
-            if result.is_err() {
-                cx.answer("Sorry, a fatal error happened, please report this to my maintainer").await?;
-                return next(Dialogue::Begin(NoData));
-            }
-            let result = result.unwrap();
+            let result = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    on_error(e, &cx.update, &cx.requester, "on code redeemal").await;
+                    return next(Dialogue::Begin(NoData));
+                }
+            };
             match result {
-                userdb::db::CodeRedeemalResult::Redeemed => {
-                    cx.answer("Success! You reedemed the code successfully, as a result you gained one more point!\n\nCheck your points with /points").await?;
+                userdb::db::CodeRedeemalResult::Redeemed(gained) => {
+                    let label = code_id.label.as_ref().map(|label| format!(" from {}", label)).unwrap_or_default();
+                    cx.answer(format!("Success! You reedemed the code{} successfully, as a result you gained {}!\n\nCheck your points with /points", label, points_text(gained))).await?;
                 },
                 userdb::db::CodeRedeemalResult::AlreadyRedeemed => {
                     cx.answer("Sorry, it looks like you already redeemed this code.").await?;
@@ -92,10 +298,28 @@ pub async fn redeem_code_cmd(
                 userdb::db::CodeRedeemalResult::NonExistingCode => {
                     cx.answer("Sorry, it looks like this code does not exist.").await?;
                 },
+                userdb::db::CodeRedeemalResult::Exhausted => {
+                    cx.answer("Sorry, this code has already been used as many times as it could be.").await?;
+                },
+                userdb::db::CodeRedeemalResult::NotAllowed => {
+                    cx.answer("Sorry, this is a personal code and it belongs to someone else.").await?;
+                },
+                userdb::db::CodeRedeemalResult::Expired => {
+                    let expired_when = code_id.expires_when.map(|expires_when| format!(" on {}", format_timestamp(expires_when))).unwrap_or_default();
+                    cx.answer(format!("Sorry, this code has expired{}.", expired_when)).await?;
+                },
             }
         },
         None => {
-            cx.reply_to("Sorry, code not found. be sure to have written it correctly").await?;
+            let mistyped = {
+                let raffle_db = crate::DB_INSTANCE.lock().await;
+                raffle_db.get_code_format().looks_mistyped(&code_string)
+            };
+            if mistyped {
+                cx.reply_to("Sorry, it looks like there is a typo in this code, please check it and try again").await?;
+            } else {
+                cx.reply_to("Sorry, code not found. be sure to have written it correctly").await?;
+            }
         }
     }
     next(Dialogue::Begin(NoData))
@@ -0,0 +1,235 @@
+use teloxide::prelude::*;
+use teloxide::types::{ParseMode, User};
+use teloxide::utils::html;
+use userdb::db::{Raffle, Referee, SlugClaimResult, UserID, UserSettings};
+use userdb::db_instances::{MAX_SLUG_LENGTH, MIN_SLUG_LENGTH};
+use crate::commands::Context;
+use crate::utils::*;
+
+use super::{dialogues::*, RaffleBot};
+use super::start::make_slug_link;
+
+// Without a name it shows the link the partecipant already has
+pub async fn my_ref_cmd(
+    slug: String,
+    ctx: Context) -> TransitionOut<Dialogue> {
+    let user_id = match ctx.update.from() {
+        Some(user) => user.id,
+        None => {
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    if is_admin(user_id) {
+        ctx.answer("You can't refer people as an admin, silly.").await?;
+        return next(Dialogue::Begin(NoData));
+    }
+    // Only partecipants can refer, so only they can take a name
+    let current = {
+        let raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.get_ongoing_raffles().and_then(|raffles| {
+            for raffle in raffles {
+                if raffle_db.is_partecipant(raffle.raffle_id, user_id)? {
+                    return raffle_db.get_referral_slug(user_id).map(Some);
+                }
+            }
+            Ok(None)
+        })
+    };
+    let current = match current {
+        Ok(Some(current)) => current,
+        Ok(None) => {
+            ctx.answer("Sorry, you must be a member of a raffle to get a referral link, type /start to see the ongoing ones.").await?;
+            return next(Dialogue::Begin(NoData));
+        }
+        Err(e) => {
+            on_error(e, &ctx.update, &ctx.requester, "on myref: check partecipant").await;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let me = ctx.requester.get_me().await?.user.username.unwrap_or_default();
+    let slug = slug.trim();
+    if slug.is_empty() {
+        match current {
+            Some(current) => {
+                ctx.answer(format!("Your referral link is {}\nType /myref NAME to change it.", make_slug_link(&me, &current))).await?;
+            },
+            None => {
+                ctx.answer("Type /myref NAME to get a referral link with that name, e.g. /myref marco").await?;
+            }
+        }
+        return next(Dialogue::Begin(NoData));
+    }
+    let result = {
+        let mut raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.claim_referral_slug(user_id, slug)
+    };
+    match result {
+        Ok(SlugClaimResult::Claimed(slug)) => {
+            let previous = match current {
+                Some(current) if current != slug => format!("\nYour old link with {} doesn't work anymore.", current),
+                _ => String::new()
+            };
+            ctx.answer(format!("Done! Your referral link is {}\nIt works in every raffle you join.{}", make_slug_link(&me, &slug), previous)).await?;
+        },
+        Ok(SlugClaimResult::Taken) => {
+            ctx.answer("Sorry, someone else has already taken that name, please try another one.").await?;
+        },
+        Ok(SlugClaimResult::Reserved) => {
+            ctx.answer("Sorry, that name is reserved, please try another one.").await?;
+        },
+        Ok(SlugClaimResult::Invalid) => {
+            ctx.answer(format!("Sorry, a name must be {} to {} letters, digits and _, starting with a letter.", MIN_SLUG_LENGTH, MAX_SLUG_LENGTH)).await?;
+        },
+        Err(e) => {
+            on_error(e, &ctx.update, &ctx.requester, "on myref: claim slug").await;
+        }
+    }
+    next(Dialogue::Begin(NoData))
+}
+
+// Tells the referrer their link worked, unless they turned that off with /notifications
+pub async fn notify_referrer(raffle: &Raffle, referrer: UserID, referee: &User, bot: &RaffleBot) {
+    let found = {
+        let raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.get_user_settings(referrer).and_then(|settings|
+            Ok((settings, raffle_db.get_partecipant(raffle.raffle_id, referrer)?)))
+    };
+    let partecipant = match found {
+        Ok((settings, Some(partecipant))) if settings.referral_notifications => partecipant,
+        Ok(_) => return,
+        Err(e) => {
+            log::error!("While notifying referrer {} of raffle {}: {}", referrer, raffle.raffle_id, e);
+            return;
+        }
+    };
+    let message = format!("<b>{}</b> joined <b>{}</b> through your link, you gained {} and now have {} points!
+Type /notifications off to stop these messages.",
+        html::escape(&referee.full_name()), html::escape(&raffle.raffle_name),
+        points_text(raffle.scoring_rules.referral_points), partecipant.priority);
+    // Fails when the referrer blocked the bot, the referral counts all the same
+    if let Err(e) = bot.send_message(referrer, message).parse_mode(ParseMode::Html).await {
+        log::warn!("Could not notify referrer {}: {}", referrer, e);
+    }
+}
+
+const ON: &str = "on";
+const OFF: &str = "off";
+
+// Without an argument it shows the current setting
+pub async fn notifications_cmd(
+    arg: String,
+    ctx: Context) -> TransitionOut<Dialogue> {
+    let user_id = match ctx.update.from() {
+        Some(user) => user.id,
+        None => {
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let referral_notifications = match arg.trim().to_lowercase().as_str() {
+        "" => None,
+        ON => Some(true),
+        OFF => Some(false),
+        _ => {
+            ctx.answer(format!("Please type /notifications {} or /notifications {}.", ON, OFF)).await?;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let settings = {
+        let mut raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.get_user_settings(user_id).and_then(|settings| match referral_notifications {
+            Some(referral_notifications) => {
+                let settings = UserSettings { referral_notifications };
+                raffle_db.set_user_settings(user_id, settings)?;
+                Ok(settings)
+            },
+            None => Ok(settings)
+        })
+    };
+    match settings {
+        Ok(settings) if settings.referral_notifications => {
+            ctx.answer(format!("You get a message whenever someone joins through your link, type /notifications {} to stop them.", OFF)).await?;
+        },
+        Ok(_) => {
+            ctx.answer(format!("You don't get a message when someone joins through your link, type /notifications {} to get them.", ON)).await?;
+        },
+        Err(e) => {
+            on_error(e, &ctx.update, &ctx.requester, "on notifications").await;
+        }
+    }
+    next(Dialogue::Begin(NoData))
+}
+
+// Telegram messages are limited in length, so only the latest referees of each raffle are listed
+const REFEREES_LISTED: usize = 30;
+
+pub async fn my_referrals_cmd(
+    ctx: Context) -> TransitionOut<Dialogue> {
+    let user_id = match ctx.update.from() {
+        Some(user) => user.id,
+        None => {
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    if is_admin(user_id) {
+        ctx.answer("You can't refer people as an admin, silly.").await?;
+        return next(Dialogue::Begin(NoData));
+    }
+    let raffle_referees = {
+        let raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.get_ongoing_raffles().and_then(|raffles| {
+            let mut raffle_referees = vec![];
+            for raffle in raffles {
+                if raffle_db.is_partecipant(raffle.raffle_id, user_id)? {
+                    let referees = raffle_db.get_referees(raffle.raffle_id, user_id)?;
+                    raffle_referees.push((raffle, referees));
+                }
+            }
+            Ok(raffle_referees)
+        })
+    };
+    let raffle_referees = match raffle_referees {
+        Ok(raffle_referees) => raffle_referees,
+        Err(e) => {
+            on_error(e, &ctx.update, &ctx.requester, "on myreferrals").await;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    if raffle_referees.is_empty() {
+        ctx.answer("Sorry, you must be a member of a raffle to refer people, type /start to see the ongoing ones.").await?;
+        return next(Dialogue::Begin(NoData));
+    }
+    let mut sections = vec![];
+    for (raffle, referees) in raffle_referees.iter() {
+        let mut section = format!("<b>{}</b>\n{}", html::escape(&raffle.raffle_name), referrals_summary(referees));
+        let skipped = referees.len().saturating_sub(REFEREES_LISTED);
+        for referee in referees.iter().skip(skipped) {
+            let tag = get_user_tag(referee.user_id, target_chat(), &ctx.requester).await
+                .unwrap_or_else(|_| format!("user {}", referee.user_id));
+            let status = match referee.left_when {
+                None => "still in the raffle".to_owned(),
+                Some(left_when) => format!("left on {}", format_timestamp(left_when)),
+            };
+            section.push_str(&format!("\n{} - joined on {}, {}, {}", tag, format_timestamp(referee.joined_when), status, points_text(referee.points)));
+        }
+        if skipped > 0 {
+            section.push_str(&format!("\n... and {} who joined earlier", skipped));
+        }
+        sections.push(section);
+    }
+    ctx.answer(sections.join("\n\n"))
+        .parse_mode(ParseMode::Html)
+        .await?;
+    next(Dialogue::Begin(NoData))
+}
+
+// e.g. "3 people joined through your link, 2 still in the raffle: you earned 3 points."
+fn referrals_summary(referees: &[Referee]) -> String {
+    if referees.is_empty() {
+        return "Nobody joined through your link yet, type /myref to get it.".to_owned();
+    }
+    let still_in = referees.iter().filter(|referee| referee.left_when.is_none()).count();
+    let points = referees.iter().map(|referee| referee.points).sum();
+    format!("{} joined through your link, {} still in the raffle: you earned {}.",
+        if referees.len() == 1 { "1 person".to_owned() } else { format!("{} people", referees.len()) },
+        still_in, points_text(points))
+}
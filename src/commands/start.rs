@@ -2,38 +2,209 @@ use std::{str::FromStr};
 use serde::{Serialize, Deserialize};
 use teloxide::{prelude::*, payloads::SendMessageSetters};
 use teloxide::types::{InputFile, ParseMode};
-use userdb::db::{UserID, RaffleDB, RegistrationStatus};
+use userdb::db::{UserID, RaffleDbError, RaffleID, RaffleResult, ReferralToken, RegistrationStatus};
+use userdb::db_instances::{normalize_campaign, normalize_slug};
 
 use crate::commands::admin::RaffleDescription;
 use crate::commands::Context;
 use crate::utils::*;
 
 use super::{dialogues::*, RaffleBot};
+use super::redeem::redeem_code_cmd;
+use super::referrals::notify_referrer;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct StartData {
-    pub referrer: Option<UserID>
+    pub raffle: Option<RaffleID>,
+    pub referrer: Option<UserID>, // Only in the links made before referral tokens
+    pub referral_token: Option<String>, // Stands for both the raffle and the referrer
+    pub referral_slug: Option<String>, // Chosen by the referrer with /myref, the same in every raffle
+    pub code: Option<String>, // Redeemed right after joining
+    pub campaign: Option<String> // Where the link was shared, e.g. ig_story or poster_milan
+}
+
+// Telegram only passes start payloads up to this long, made of letters, digits, _ and -
+const MAX_PAYLOAD_LENGTH: usize = 64;
+const PAYLOAD_VERSION: &str = "v1";
+const FIELD_SEPARATOR: char = '-';
+const RAFFLE_FIELD: char = 'r';
+const REFERRER_FIELD: char = 'f';
+const REFERRAL_TOKEN_FIELD: char = 'i';
+const REFERRAL_SLUG_FIELD: char = 's';
+const CODE_FIELD: char = 'c';
+const CAMPAIGN_FIELD: char = 't';
+// Vanity links are kept short and readable, e.g. r_marco
+const SLUG_PAYLOAD: &str = "r_";
+
+impl StartData {
+    /*
+    Payloads are v1 followed by the fields that are set, each one a letter and a value, joined by -
+    e.g. v1-r3-f12345-tposter_milan, see the *_FIELD constants for the letters.
+    None if the payload doesn't fit in a start link.
+    */
+    pub fn to_payload(&self) -> Option<String> {
+        let mut payload = PAYLOAD_VERSION.to_owned();
+        let fields = [
+            (RAFFLE_FIELD, self.raffle.map(|raffle| raffle.to_string())),
+            (REFERRER_FIELD, self.referrer.map(|referrer| referrer.to_string())),
+            (REFERRAL_TOKEN_FIELD, self.referral_token.clone()),
+            (REFERRAL_SLUG_FIELD, self.referral_slug.clone()),
+            (CODE_FIELD, self.code.clone()),
+            (CAMPAIGN_FIELD, self.campaign.clone())
+        ];
+        for (field, value) in fields.iter() {
+            if let Some(value) = value {
+                payload.push(FIELD_SEPARATOR);
+                payload.push(*field);
+                payload.push_str(value);
+            }
+        }
+        let fits = payload.len() <= MAX_PAYLOAD_LENGTH
+            && payload.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == FIELD_SEPARATOR);
+        // A value with a separator in it would be read back as two fields
+        let unambiguous = self.code.iter().chain(self.referral_token.iter()).chain(self.referral_slug.iter()).chain(self.campaign.iter()).all(|value| !value.contains(FIELD_SEPARATOR));
+        if fits && unambiguous { Some(payload) } else { None }
+    }
+
+    fn from_v1_fields<'a>(fields: impl Iterator<Item = &'a str>) -> StartData {
+        let mut data = StartData::default();
+        for field in fields {
+            let mut chars = field.chars();
+            let (name, value) = (chars.next(), chars.as_str());
+            let parsed = match name {
+                Some(RAFFLE_FIELD) => value.parse().map(|raffle| data.raffle = Some(raffle)).is_ok(),
+                Some(REFERRER_FIELD) => value.parse().map(|referrer| data.referrer = Some(referrer)).is_ok(),
+                Some(REFERRAL_TOKEN_FIELD) if !value.is_empty() => {
+                    data.referral_token = Some(value.to_owned());
+                    true
+                },
+                Some(REFERRAL_SLUG_FIELD) => normalize_slug(value).map(|slug| data.referral_slug = Some(slug)).is_some(),
+                Some(CODE_FIELD) if !value.is_empty() => {
+                    data.code = Some(value.to_owned());
+                    true
+                },
+                Some(CAMPAIGN_FIELD) => normalize_campaign(value).map(|campaign| data.campaign = Some(campaign)).is_some(),
+                _ => false
+            };
+            if !parsed {
+                log::warn!("Ignoring the start payload field {:?}", field);
+            }
+        }
+        data
+    }
 }
 
 impl FromStr for StartData {
     type Err = std::io::Error;
 
+    // Besides the versioned payloads of to_payload and the r_<slug> vanity links, the referrer id of the first links is still accepted
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(StartData {
-            referrer: match s.parse::<i64>() {
-                Ok(num) => Some(num),
-                Err(_) => None
+        let mut fields = s.split(FIELD_SEPARATOR);
+        match fields.next() {
+            Some(PAYLOAD_VERSION) => return Ok(StartData::from_v1_fields(fields)),
+            Some(version) if version.len() > 1 && version.starts_with('v') && version[1..].chars().all(|c| c.is_ascii_digit()) => {
+                // Made by a newer release, the user still gets the plain /start
+                log::warn!("Ignoring the start payload {:?}, its version is unknown", s);
+                return Ok(StartData::default());
+            },
+            _ => {}
+        }
+        if let Some(slug) = s.strip_prefix(SLUG_PAYLOAD) {
+            let referral_slug = normalize_slug(slug);
+            if referral_slug.is_none() {
+                log::warn!("Ignoring the start payload {:?}, it is not a valid slug", s);
             }
+            return Ok(StartData {
+                referral_slug,
+                ..StartData::default()
+            });
+        }
+        let referrer = s.parse::<UserID>().ok();
+        if !s.is_empty() && referrer.is_none() {
+            log::warn!("Ignoring the start payload {:?}, it is not in any known format", s);
+        }
+        Ok(StartData {
+            referrer,
+            ..StartData::default()
         })
     }
 }
 
-fn make_referral_link(bot_name: String, user_id: UserID) -> String {
-    format!("https://t.me/{}?start={}", bot_name, user_id)
+// None when the data can't fit in a start link, like some codes generated before codes were readable
+pub fn make_start_link(bot_name: &str, data: &StartData) -> Option<String> {
+    data.to_payload().map(|payload| format!("https://t.me/{}?start={}", bot_name, payload))
+}
+
+pub fn make_referral_link(bot_name: &str, token: &ReferralToken) -> String {
+    let data = StartData {
+        referral_token: Some(token.token.clone()),
+        ..StartData::default()
+    };
+    make_start_link(bot_name, &data).expect("Referral tokens always fit in a start link")
+}
+
+pub fn make_slug_link(bot_name: &str, slug: &str) -> String {
+    format!("https://t.me/{}?start={}{}", bot_name, SLUG_PAYLOAD, slug)
+}
+
+// The raffle and the referrer a start link stands for
+async fn resolve_referral(data: &StartData) -> RaffleResult<(Option<RaffleID>, Option<UserID>)> {
+    if let Some(slug) = &data.referral_slug {
+        let raffle_db = crate::DB_INSTANCE.lock().await;
+        let referrer = match raffle_db.find_referral_slug(slug)? {
+            Some(referrer) => referrer,
+            None => {
+                log::warn!("Ignoring the unknown referral slug {:?}", slug);
+                return Ok((data.raffle, None));
+            }
+        };
+        // Slugs are the same in every raffle: when the link doesn't say, it is the only raffle the referrer is in
+        let raffle = match data.raffle {
+            Some(raffle) => Some(raffle),
+            None => {
+                let mut referrer_raffles = vec![];
+                for raffle in raffle_db.get_ongoing_raffles()? {
+                    if raffle_db.is_partecipant(raffle.raffle_id, referrer)? {
+                        referrer_raffles.push(raffle.raffle_id);
+                    }
+                }
+                match referrer_raffles.as_slice() {
+                    [raffle] => Some(*raffle),
+                    _ => None
+                }
+            }
+        };
+        return Ok((raffle, Some(referrer)));
+    }
+    if let Some(token) = &data.referral_token {
+        let found = {
+            let raffle_db = crate::DB_INSTANCE.lock().await;
+            raffle_db.find_referral_token(token)?
+        };
+        return Ok(match found {
+            Some(found) => (Some(found.raffle_id), Some(found.user_id)),
+            None => {
+                log::warn!("Ignoring the unknown referral token {:?}", token);
+                (data.raffle, None)
+            }
+        });
+    }
+    if data.referrer.is_some() && !numeric_referral_links() {
+        log::info!("Ignoring the numeric referral of {:?}, they are turned off", data.referrer);
+        return Ok((data.raffle, None));
+    }
+    Ok((data.raffle, data.referrer))
+}
+
+pub fn make_code_link(bot_name: &str, code: &str) -> Option<String> {
+    make_start_link(bot_name, &StartData {
+        code: Some(code.to_owned()),
+        ..StartData::default()
+    })
 }
 
 pub async fn start_cmd(
-    referrer: Option<UserID>,
+    data: StartData,
     cx: Context) -> TransitionOut<Dialogue> {
 
     let user_id = match cx.update.from() {
@@ -44,46 +215,67 @@ pub async fn start_cmd(
         // show admin keyboard
         cx.answer("Available commands for admins:
 /startraffle to start a new raffle
-/endraffle to end an ongoing raffle
-/generatecode [usages=illimited, a number, once] to generate a redeemable code
+/endraffle [raffle number] to end an ongoing raffle
+/stats [raffle number] to see how an ongoing raffle is going
+/generatecode [usages=illimited, a number, once] [raffle number] [points=N] [valid=2h or until=YYYY-MM-DD HH:MM] [for=USER_ID,...] [label=TEXT] to generate a redeemable code, worth the raffle's code points unless points= is given, dates are in UTC
+/bulkcodes COUNT followed by the same arguments as /generatecode to generate many codes at once and get them as a CSV file
+/codes [page] [raffle number] to list the codes of a raffle
+/code CODE to see a code and who redeemed it
+/revokecode CODE to stop a code from being redeemed
+/qr code CODE, /qr ref USER_ID [raffle number] or /qr raffle [raffle number], optionally followed by campaign=TAG, to get a QR code for posters, scanning it joins the raffle and redeems the code if any; /stats counts the joins of each campaign
+/grant USER_ID POINTS [raffle=N] REASON to give points to a partecipant
+/deduct USER_ID POINTS [raffle=N] REASON to take points from a partecipant
+The raffle number is only needed when more than one raffle is running.
 ")        .await?;
         next(Dialogue::Begin(NoData))
     } else {
-        let ongoing_raffle = {
+        let (raffle, referrer) = match resolve_referral(&data).await {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                on_error(e, &cx.update, &cx.requester, "on start: resolve referral").await;
+                return next(Dialogue::Begin(NoData));
+            }
+        };
+        if let Some(code) = data.code {
+            return join_and_redeem(code, referrer, data.campaign, cx).await;
+        }
+        if raffle.is_some() || referrer.is_some() || data.campaign.is_some() {
+            return join_cmd(RaffleSelection(raffle), referrer, data.campaign, cx).await;
+        }
+        let ongoing_raffles = {
             let raffle_db = crate::DB_INSTANCE.lock().await;
-            raffle_db.get_ongoing_raffle()
+            raffle_db.get_ongoing_raffles()
         };
-        let ongoing_raffle = match ongoing_raffle {
-            Ok(thing) => thing,
+        let ongoing_raffles = match ongoing_raffles {
+            Ok(raffles) => raffles,
             Err(e) => {
-                on_error(e, &cx.update, &cx.requester, "on start: get ongoing raffle").await;
+                on_error(e, &cx.update, &cx.requester, "on start: get ongoing raffles").await;
                 return next(Dialogue::Begin(NoData));
             }
         };
-        match ongoing_raffle {
-            None => {
-                cx.answer("Hello! At the moment there are no raffles running, so please wait for an announcment!").await?;
-                next(Dialogue::Begin(NoData))
-            }
-            Some(raffle) => {
-                match referrer {
-                    Some(_) => {
-                        join_cmd(referrer, cx).await
-                    }
-                    None => {
-                        let message_copy = serde_json::from_str::<RaffleDescription>(&raffle.raffle_description)
-                            .expect("Failed to parse message from database")
-                            .clone();
-                            send_raffle_desc_into_chat(&cx.requester, message_copy, cx.chat_id()).await;
-                        cx.answer("In order to join the raffle please type /join.")
-                        .await?;
-                        next(Dialogue::AwaitingJoinChannel(AwaitingJoinChannelState{
-                            referrer
-                        }))
-                    }
-                }
+        if ongoing_raffles.is_empty() {
+            cx.answer("Hello! At the moment there are no raffles running, so please wait for an announcment!").await?;
+            return next(Dialogue::Begin(NoData));
+        }
+        for raffle in ongoing_raffles.iter() {
+            let message_copy = serde_json::from_str::<RaffleDescription>(&raffle.raffle_description)
+                .expect("Failed to parse message from database")
+                .clone();
+            send_raffle_desc_into_chat(&cx.requester, message_copy, cx.chat_id()).await;
+            if ongoing_raffles.len() > 1 {
+                cx.answer(format!("To join this raffle please type /join {}.", raffle.raffle_id))
+                .await?;
             }
         }
+        if ongoing_raffles.len() == 1 {
+            cx.answer("In order to join the raffle please type /join.")
+            .await?;
+        }
+        next(Dialogue::AwaitingJoinChannel(AwaitingJoinChannelState{
+            raffle: None,
+            referrer: None,
+            campaign: None
+        }))
     }
 }
 /* This code is kept because atm serde_json can't deserialize Messages, should it be resolved send_raffle_desc_into_chat is going to be replaced with this
@@ -123,7 +315,9 @@ async fn send_raffle_desc_into_chat(bot: &RaffleBot, message: RaffleDescription,
 }
 
 pub async fn join_cmd(
+    selection: RaffleSelection,
     referrer: Option<UserID>,
+    campaign: Option<String>,
     cx: Context) -> TransitionOut<Dialogue> {
     let user_id = match cx.update.from() {
         Some(u) => u.id,
@@ -144,12 +338,37 @@ Type /start to see what you can do as an admin")
             .parse_mode(ParseMode::Html)
             .await?;
         return next(Dialogue::AwaitingJoinChannel(AwaitingJoinChannelState{
-            referrer
+            raffle: selection.0,
+            referrer,
+            campaign
         }));
     }
+    let raffle = match choose_ongoing_raffle(selection).await {
+        Ok(RaffleChoice::Chosen(raffle)) => raffle,
+        Ok(choice @ RaffleChoice::Ambiguous(_)) => {
+            cx.reply_to(choice.explain("/join"))
+                .parse_mode(ParseMode::Html)
+                .await?;
+            // The referrer and the campaign of the link still count once the user picks the raffle
+            return next(Dialogue::AwaitingJoinRaffle(AwaitingJoinRaffleState{
+                referrer,
+                campaign
+            }));
+        }
+        Ok(choice) => {
+            cx.reply_to(choice.explain("/join"))
+                .parse_mode(ParseMode::Html)
+                .await?;
+            return next(Dialogue::Begin(NoData));
+        }
+        Err(e) => {
+            on_error(e, &cx.update, &cx.requester, "on registration: choose raffle").await;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
     let is_partecipant = {
         let raffle_db = crate::DB_INSTANCE.lock().await;
-        raffle_db.is_partecipant(user_id)
+        raffle_db.is_partecipant(raffle.raffle_id, user_id)
     };
     let is_partecipant = match is_partecipant {
             Ok(result) => result, 
@@ -163,12 +382,15 @@ Type /start to see what you can do as an admin")
 As a partecipant, you can issue the following commands:
 /points to see how many points you have
 /redeem CODE to redeem a code 
+/myref NAME to get a referral link with a name of your choice
+/myreferrals to see who joined through your link
+/notifications on or off to choose whether you get a message when someone joins through your link
 /leave to leave the raffle        
 ").await?;
     } else {
         let result = {
             let mut raffle_db = crate::DB_INSTANCE.lock().await;
-            raffle_db.register_partecipant(user_id, referrer)
+            raffle_db.register_partecipant(raffle.raffle_id, user_id, referrer, campaign.as_deref())
         };
         match result {
             Ok(RegistrationStatus::NotRegistered) => {
@@ -180,27 +402,105 @@ As a partecipant, you can issue the following commands:
             Err(e) => {
                 on_error(e, &cx.update, &cx.requester, "on registration").await;
             },
-            _ => {
+            Ok(status) => {
+                // Only a referral recorded by this registration is notified
+                if let (RegistrationStatus::Referred(_, recorded_referrer), Some(referee)) = (&status, cx.update.from()) {
+                    notify_referrer(&raffle, *recorded_referrer, referee, &cx.requester).await;
+                }
                 let me = cx.requester.get_me().await?.user.username.expect("Could not fetch the username of this bot!");
-                let referral = make_referral_link(me, user_id);
-                cx.reply_to("<b>Welcome to this raffle!</b>
+                let token = {
+                    let mut raffle_db = crate::DB_INSTANCE.lock().await;
+                    raffle_db.get_referral_token(raffle.raffle_id, user_id)
+                };
+                let referral = match token {
+                    Ok(Some(token)) => make_referral_link(&me, &token),
+                    Ok(None) => {
+                        on_error(RaffleDbError::NotFound(format!("partecipant {} has no referral token", user_id)), &cx.update, &cx.requester, "on registration: referral token").await;
+                        return next(Dialogue::Begin(NoData));
+                    }
+                    Err(e) => {
+                        on_error(e, &cx.update, &cx.requester, "on registration: referral token").await;
+                        return next(Dialogue::Begin(NoData));
+                    }
+                };
+                let rules = raffle.scoring_rules;
+                cx.reply_to(format!("<b>Welcome to this raffle!</b>
                 
-You gained one point for joining, use /redeem to redeem additional codes and /points to see your points!
+You gained {} for joining, use /redeem to redeem additional codes and /points to see your points!
 
-<b>Below you will find a referral link you can share with other people</b>: if they join using your link you will gain an additional point")
+<b>Below you will find a referral link you can share with other people</b>: if they join using your link you will gain {} for each of them. Type /myref NAME for a link with a name of your choice", points_text(rules.join_points), points_text(rules.referral_points)))
                 .parse_mode(ParseMode::Html)
                 .await?;
                 cx.answer(referral).await?;
             }
         }
-        // TODO Notify referrer in case
     }
     next(Dialogue::Begin(NoData))
 }
 
+// A code link joins the raffle of the code, if needed, and then redeems it
+async fn join_and_redeem(
+    code_string: String,
+    referrer: Option<UserID>,
+    campaign: Option<String>,
+    cx: Context) -> TransitionOut<Dialogue> {
+    let user_id = match cx.update.from() {
+        Some(u) => u.id,
+        None => {
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    let found = {
+        let raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.find_raffle_code(&code_string).and_then(|code| match code {
+            Some(code) => {
+                let raffle = raffle_db.get_raffle(code.raffle_id)?;
+                let is_partecipant = raffle_db.is_partecipant(code.raffle_id, user_id)?;
+                Ok(Some((code, raffle, is_partecipant)))
+            },
+            None => Ok(None)
+        })
+    };
+    let (code, is_partecipant) = match found {
+        Ok(Some((code, Some(raffle), is_partecipant))) if raffle.ended_when.is_none() => (code, is_partecipant),
+        Ok(Some(_)) => {
+            cx.answer("Sorry, the raffle of this code has already ended. Type /start to see the ongoing ones.").await?;
+            return next(Dialogue::Begin(NoData));
+        }
+        Ok(None) => {
+            cx.answer("Sorry, the code in this link does not exist. Type /start to see the ongoing raffles.").await?;
+            return next(Dialogue::Begin(NoData));
+        }
+        Err(e) => {
+            on_error(e, &cx.update, &cx.requester, "on code link").await;
+            return next(Dialogue::Begin(NoData));
+        }
+    };
+    if !is_partecipant {
+        let stage = join_cmd(RaffleSelection(Some(code.raffle_id)), referrer, campaign, cx.clone()).await?;
+        let joined = {
+            let raffle_db = crate::DB_INSTANCE.lock().await;
+            raffle_db.is_partecipant(code.raffle_id, user_id)
+        };
+        match joined {
+            Ok(true) => {},
+            Ok(false) => {
+                cx.answer(format!("Once you have joined, type /redeem {} to get the points of your code.", code.code)).await?;
+                return Ok(stage);
+            }
+            Err(e) => {
+                on_error(e, &cx.update, &cx.requester, "on code link: check registration").await;
+                return next(Dialogue::Begin(NoData));
+            }
+        }
+    }
+    redeem_code_cmd(code.code, cx).await
+}
+
 const YES: &str = "yes";
 
 pub async fn leave_cmd(
+    selection: RaffleSelection,
     cx: Context) -> TransitionOut<Dialogue> {
     let user_id = match cx.update.from() {
         Some(u) => u.id,
@@ -212,16 +512,24 @@ pub async fn leave_cmd(
         cx.answer("You can't leave the chat as an admin, silly!").await?;
         return next(Dialogue::Begin(NoData));
     }
+    let raffle = match choose_raffle_or_explain(selection, "/leave", &cx, "on leave: choose raffle").await? {
+        Some(raffle) => raffle,
+        None => return next(Dialogue::Begin(NoData))
+    };
     cx.answer("Do you really want to leave the raffle? Please type yes (lowercase!) or anything else to abort").await?;
-    next(Dialogue::AwaitingLeaveAnswer(LeaveState))
+    next(Dialogue::AwaitingLeaveAnswer(LeaveState {
+        raffle_id: raffle.raffle_id
+    }))
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct LeaveState;
+pub struct LeaveState {
+    pub raffle_id: RaffleID
+}
 
 #[teloxide(subtransition)]
 async fn leave_got_answer(
-    _state: LeaveState,
+    state: LeaveState,
     cx: TransitionIn<RaffleBot>,
     _ans: String) -> TransitionOut<Dialogue> {
     let user_id = match cx.update.from() {
@@ -234,7 +542,7 @@ async fn leave_got_answer(
         Some(YES) => {
             let remove_status = {
                 let mut raffle_db = crate::DB_INSTANCE.lock().await;
-                raffle_db.remove_partecipant(user_id)
+                raffle_db.remove_partecipant(state.raffle_id, user_id)
             };
             match remove_status {
                 Ok(true) => {
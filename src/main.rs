@@ -14,7 +14,10 @@ mod utils;
 use commands::*;
 use std::sync::Arc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
+use userdb::db::RaffleDB;
 use userdb::db_instances::sqlite_instance::SQLiteInstance;
+use userdb::db_instances::memory_instance::MemoryInstance;
+use utils::DatabaseBackend;
 
 use async_mutex::Mutex;
 use teloxide::{prelude::*, 
@@ -29,8 +32,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 lazy_static! {
-    pub static ref DB_INSTANCE : Mutex<SQLiteInstance> = Mutex::new(SQLiteInstance::create("raffle_db.db")
-                                                .expect("Failure to open userdb"));
+    pub static ref DB_INSTANCE : Mutex<Box<dyn RaffleDB + Send>> = Mutex::new(open_database());
+}
+
+fn open_database() -> Box<dyn RaffleDB + Send> {
+    let mut database: Box<dyn RaffleDB + Send> = match utils::database_backend() {
+        DatabaseBackend::Sqlite => Box::new(SQLiteInstance::create("raffle_db.db")
+                                    .unwrap_or_else(|e| panic!("Failure to open userdb: {}", e))),
+        DatabaseBackend::Memory => {
+            log::warn!("Using the in-memory database, every raffle will be lost when the bot stops");
+            Box::new(MemoryInstance::create())
+        }
+    };
+    database.set_code_format(utils::configured_code_format())
+        .unwrap_or_else(|e| panic!("Invalid code_format in the config: {}", e));
+    database
 }
 
 
@@ -46,7 +62,7 @@ pub async fn handle_dialogue(ctx: UpdateWithCx<RaffleBot, Message>, dialogue: Di
             let name = me.user.username.expect("Must have an username");
             let cmd = Command::parse(ans.as_str(), name);
             if cmd.is_ok() {
-                handle_action(ctx, cmd.unwrap()).await
+                handle_action(ctx, cmd.unwrap(), dialogue).await
             } else {
                 dialogue.react(ctx, ans).await
             }
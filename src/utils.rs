@@ -1,18 +1,56 @@
-use std::{io::BufReader, collections::HashSet, error::Error};
+use std::{io::BufReader, collections::HashSet, str::FromStr};
 use async_mutex::Mutex;
 
 use serde::Deserialize;
-use teloxide::{types::{Chat, Message, ChatKind, ChatPublic}, prelude::Requester, ApiError, RequestError};
-use userdb::db::UserID;
+use teloxide::{types::{Chat, Message, ChatKind, ChatPublic, ParseMode}, prelude::*, utils::html, ApiError, RequestError};
+use userdb::db::{UserID, Raffle, RaffleDbError, RaffleID, RaffleResult, Timestamp};
+use userdb::codes::CodeFormat;
+// The clock the database judges expiry with, so the bot never disagrees with it
+pub use userdb::db_instances::timestamp_now;
 use lazy_static::lazy_static;
 
-use crate::commands::RaffleBot;
+use crate::commands::{Context, RaffleBot};
 
 #[derive(Deserialize)]
 struct Config {
     manager: UserID,
     target_chat: i64,
-    admin_users: HashSet<i64>
+    admin_users: HashSet<i64>,
+    #[serde(default)]
+    database: DatabaseBackend,
+    #[serde(default)]
+    code_format: CodeFormatConfig,
+    // Whether the referral links with the referrer's user id, made before referral tokens, still count
+    #[serde(default = "default_numeric_referral_links")]
+    numeric_referral_links: bool
+}
+
+fn default_numeric_referral_links() -> bool {
+    true
+}
+
+// How new codes look, anything left out is as in CodeFormat::default()
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct CodeFormatConfig {
+    alphabet: Option<String>,
+    length: Option<usize>,
+    prefix: String,
+    check_character: bool
+}
+
+// Where the raffles are stored, "memory" loses everything on restart and is meant for demos and dry runs
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    Sqlite,
+    Memory
+}
+
+impl Default for DatabaseBackend {
+    fn default() -> Self {
+        DatabaseBackend::Sqlite
+    }
 }
 
 fn load_config(file: &str) -> Result<Config, Box<dyn std::error::Error>> {
@@ -29,6 +67,26 @@ pub fn target_chat() -> i64 {
     CONFIG.target_chat
 }
 
+pub fn database_backend() -> DatabaseBackend {
+    CONFIG.database
+}
+
+pub fn numeric_referral_links() -> bool {
+    CONFIG.numeric_referral_links
+}
+
+// As written in the config, the database validates it and tells the format it uses with get_code_format
+pub fn configured_code_format() -> CodeFormat {
+    let config = &CONFIG.code_format;
+    let default = CodeFormat::default();
+    CodeFormat {
+        alphabet: config.alphabet.clone().unwrap_or(default.alphabet),
+        length: config.length.unwrap_or(default.length),
+        prefix: config.prefix.clone(),
+        check_character: config.check_character
+    }
+}
+
 
 pub fn is_admin(user_id: UserID) -> bool {
     CONFIG.admin_users.contains(&user_id) || is_manager(user_id)
@@ -67,6 +125,136 @@ pub async fn generate_invite_for_target_chat(bot: &RaffleBot) -> Result<String,
     Ok(format!("<a href=\"{0}\">{1}</a>", invite_link, chat_fullname))
 }
 
+pub fn points_text(points: i64) -> String {
+    if points.abs() == 1 {
+        format!("{} point", points)
+    } else {
+        format!("{} points", points)
+    }
+}
+
+// Dates are shown and read in UTC, the bot doesn't know where its users are
+pub fn format_timestamp(timestamp: Timestamp) -> String {
+    match i64::try_from(timestamp).ok().and_then(|timestamp| chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0)) {
+        Some(moment) => moment.format("%Y-%m-%d %H:%M UTC").to_string(),
+        None => timestamp.to_string()
+    }
+}
+
+// A PNG to print on posters, scanning it opens the link
+pub fn qr_code_png(link: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let image = qrcode::QrCode::new(link.as_bytes())?
+        .render::<image::Luma<u8>>()
+        .min_dimensions(512, 512)
+        .build();
+    let mut png = vec![];
+    image::DynamicImage::ImageLuma8(image).write_to(&mut png, image::ImageOutputFormat::Png)?;
+    Ok(png)
+}
+
+// Who can redeem a personal code, users are shown by id since they may not be in the chat
+pub fn owners_text(owners: &[UserID]) -> String {
+    owners.iter()
+        .map(|owner| format!("user {}", owner))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// The optional raffle number users can pass to commands, e.g. /join 3
+#[derive(Clone, Copy)]
+pub struct RaffleSelection(pub Option<RaffleID>);
+
+impl FromStr for RaffleSelection {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            Ok(RaffleSelection(None))
+        } else {
+            Ok(RaffleSelection(Some(s.parse()?)))
+        }
+    }
+}
+
+pub enum RaffleChoice {
+    Chosen(Raffle),
+    NoneOngoing,
+    NotFound,
+    Ambiguous(Vec<Raffle>)
+}
+
+impl RaffleChoice {
+    // What to tell the user when no raffle could be chosen, command is what they should type to pick one
+    // Commands that take the raffle as raffle=N end with the key, e.g. "/grant 1 5 raffle="
+    pub fn explain(&self, command: &str) -> String {
+        let (command, key) = match command.rsplit_once(' ') {
+            Some((command, key)) if key.ends_with('=') => (command, key),
+            _ => (command, "")
+        };
+        match self {
+            RaffleChoice::Chosen(raffle) => format!("You picked <b>{}</b>.", html::escape(&raffle.raffle_name)),
+            RaffleChoice::NoneOngoing => "There are no ongoing raffles at the moment.".to_owned(),
+            RaffleChoice::NotFound => format!("Sorry, there is no ongoing raffle with that number, type {} to see the ongoing ones.", command),
+            RaffleChoice::Ambiguous(raffles) => {
+                let list = raffles.iter()
+                    .map(|raffle| format!("{} - {}", raffle.raffle_id, html::escape(&raffle.raffle_name)))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                format!("There are several raffles running:\n{}\n\nPlease type {} followed by {}the number of the raffle, e.g. {} {}{}",
+                    list, command, key, command, key, raffles[0].raffle_id)
+            }
+        }
+    }
+}
+
+// Picks the ongoing raffle the user asked for, or the only one running if they did not ask for one
+pub async fn choose_ongoing_raffle(selection: RaffleSelection) -> RaffleResult<RaffleChoice> {
+    let mut ongoing_raffles = {
+        let raffle_db = crate::DB_INSTANCE.lock().await;
+        raffle_db.get_ongoing_raffles()?
+    };
+    Ok(match selection.0 {
+        Some(raffle_id) => match ongoing_raffles.into_iter().find(|raffle| raffle.raffle_id == raffle_id) {
+            Some(raffle) => RaffleChoice::Chosen(raffle),
+            None => RaffleChoice::NotFound
+        },
+        None => match ongoing_raffles.len() {
+            0 => RaffleChoice::NoneOngoing,
+            1 => RaffleChoice::Chosen(ongoing_raffles.remove(0)),
+            _ => RaffleChoice::Ambiguous(ongoing_raffles)
+        }
+    })
+}
+
+// Like choose_ongoing_raffle, but None means the user was already told why no raffle could be chosen
+pub async fn choose_raffle_or_explain(selection: RaffleSelection, command: &str, ctx: &Context, context: &str) -> Result<Option<Raffle>, RequestError> {
+    match choose_ongoing_raffle(selection).await {
+        Ok(RaffleChoice::Chosen(raffle)) => Ok(Some(raffle)),
+        Ok(choice) => {
+            ctx.answer(choice.explain(command))
+                .parse_mode(ParseMode::Html)
+                .await?;
+            Ok(None)
+        }
+        Err(e) => {
+            on_error(e, &ctx.update, &ctx.requester, context).await;
+            Ok(None)
+        }
+    }
+}
+
+// The id of the admin who sent the command, None when it was someone else, who is told so
+pub async fn admin_only(ctx: &Context) -> Result<Option<UserID>, RequestError> {
+    match ctx.update.from() {
+        Some(user) if is_admin(user.id) => Ok(Some(user.id)),
+        Some(_) => {
+            ctx.answer("This command can only be used by an admin.").await?;
+            Ok(None)
+        }
+        None => Ok(None)
+    }
+}
+
 pub fn is_chat_with_manager(user_id: UserID, chat: &Chat) -> bool {
     is_manager(user_id) && chat.is_private()
 }
@@ -106,9 +294,31 @@ pub async fn is_member_of_target_group(user_id: UserID, bot: &RaffleBot) -> Resu
     }
 }
 
-pub async fn on_error<'a>(err: Box<dyn Error + Send + Sync + 'a>, msg: &Message, bot: &RaffleBot, user_err: &str) {
+// What the user should be told about a database error, None when only the manager can do something about it
+fn explain_db_error(err: &RaffleDbError) -> Option<String> {
+    match err {
+        RaffleDbError::RaffleNotFound(raffle_id) =>
+            Some(format!("Sorry, there is no raffle number {}.", raffle_id)),
+        RaffleDbError::NoOngoingRaffle(_) =>
+            Some("Sorry, this raffle has already ended. Type /start to see the ongoing ones.".to_owned()),
+        RaffleDbError::InvalidUseCount(_) =>
+            Some("Sorry, a code must be usable at least once: use a positive number, once or illimited.".to_owned()),
+        RaffleDbError::ConstraintViolation(_) =>
+            Some("Sorry, that clashes with something that exists already, please try again.".to_owned()),
+        RaffleDbError::NotFound(_) | RaffleDbError::Migration(_) | RaffleDbError::Storage(_) => None
+    }
+}
+
+pub async fn on_error(err: RaffleDbError, msg: &Message, bot: &RaffleBot, user_err: &str) {
     // Inform the user that an error occurred, ONLY IN PRIVATE CHAT (to avoid possible spamming)
     let chat = &msg.chat;
+    if let Some(explanation) = explain_db_error(&err) {
+        if chat.is_private() {
+            let _ = bot.send_message(chat.id, explanation).await;
+        }
+        log::warn!("{}: {}", user_err, err);
+        return;
+    }
     if chat.is_private() {
         let _ = bot.send_message(chat.id, "Sorry! While processing your message an error has occurred, i'm signaling it to the bot manager.")
         .await;
@@ -118,16 +328,16 @@ pub async fn on_error<'a>(err: Box<dyn Error + Send + Sync + 'a>, msg: &Message,
 <b>An error occurred: {}</b>
 
 Error details:
-{:?}
+{}
 
 Message that caused the error:
 {:?}
 
-", user_err, msg, err);
+", user_err, err, msg);
     let _ = bot.send_message(CONFIG.manager, &error_message).await;
     log::error!("
 ---- ERROR -------
 {}
 ---- ERROR END ---
 ", error_message);
-}
\ No newline at end of file
+}
@@ -0,0 +1,70 @@
+extern crate userdb;
+
+use std::time::{Duration, Instant};
+use userdb::db::*;
+use userdb::db_instances::sqlite_instance::SQLiteInstance;
+
+/*
+Times the queries that read the partecipants of a big raffle.
+Run it from the userdb folder with cargo bench --bench partecipants, it is a plain main that prints one line per query.
+The database is kept in memory so that the numbers are about the queries and not the disk.
+
+On one core of a recent server the numbers are about:
+filling the raffle 15s, get_partecipants 250ms, get_partecipant x 1000 10ms, stop_raffle 350ms.
+Only a change of the order of magnitude means something: get_partecipant growing with the raffle
+means the lookup lost its index, get_partecipants taking seconds means priorities are counted once per partecipant.
+*/
+const PARTECIPANTS: UserID = 100_000;
+const CODES: usize = 20;
+const ROUNDS: u32 = 5;
+
+fn time<T, F: FnMut() -> T>(name: &str, mut f: F) -> T {
+    let mut best = Duration::MAX;
+    let mut result = None;
+    for _ in 0..ROUNDS {
+        let started = Instant::now();
+        result = Some(f());
+        best = best.min(started.elapsed());
+    }
+    println!("{:<40} best of {}: {:?}", name, ROUNDS, best);
+    result.expect("ROUNDS must be at least 1")
+}
+
+fn main() {
+    let mut db = SQLiteInstance::create(":memory:").unwrap();
+    let raffle = match db.create_raffle("Benchmark", "Benchmark description", DrawMode::Weighted, ScoringRules::default()).unwrap() {
+        RaffleCreationResult::Success(raffle) => raffle.raffle_id,
+        other => panic!("Could not start the raffle: {:?}", other)
+    };
+
+    let started = Instant::now();
+    for user_id in 0..PARTECIPANTS {
+        // A third of the partecipants were invited by someone who joined before them
+        let referrer = if user_id % 3 == 0 { Some(user_id / 3) } else { None };
+        db.register_partecipant(raffle, user_id, referrer, None).unwrap();
+    }
+    let codes = Vec::from_iter((0..CODES).map(|_| db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions::default()).unwrap()));
+    for user_id in 0..PARTECIPANTS {
+        db.redeem_code(user_id, codes[user_id as usize % CODES].unique_id).unwrap();
+        if user_id % 4 == 0 {
+            db.redeem_code(user_id, codes[(user_id as usize + 1) % CODES].unique_id).unwrap();
+        }
+    }
+    println!("{} partecipants, {} referrals and {} used codes stored in {:?}",
+        PARTECIPANTS,
+        db.get_referrals(raffle).unwrap().len(),
+        db.get_used_codes(raffle).unwrap().len(),
+        started.elapsed());
+
+    let partecipants = time("get_partecipants", || db.get_partecipants(raffle).unwrap());
+    assert_eq!(partecipants.len(), PARTECIPANTS as usize);
+    time("get_partecipant x 1000", || {
+        for user_id in (0..PARTECIPANTS).step_by(PARTECIPANTS as usize / 1000) {
+            db.get_partecipant(raffle, user_id).unwrap().unwrap();
+        }
+    });
+    let started = Instant::now();
+    let outcome = db.stop_raffle(raffle, 10).unwrap();
+    println!("{:<40} {:?}", "stop_raffle, 10 winners", started.elapsed());
+    assert_eq!(outcome.winners.len(), 10);
+}
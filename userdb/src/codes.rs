@@ -0,0 +1,116 @@
+use rand::Rng;
+use crate::db::{RaffleDbError, RaffleResult};
+
+// Capital letters and digits without the ones that look alike: 0 and O, 1 and I
+pub const READABLE_ALPHABET: &str = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/*
+How new codes look: prefix, then length characters picked from the alphabet,
+then, if check_character is set, one more character computed from the random ones
+with the Luhn mod N algorithm, so that most typos can be told apart from wrong codes.
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CodeFormat {
+    pub alphabet: String,
+    pub length: usize, // Without the prefix and the check character
+    pub prefix: String,
+    pub check_character: bool,
+}
+
+impl Default for CodeFormat {
+    fn default() -> Self {
+        CodeFormat {
+            alphabet: READABLE_ALPHABET.to_owned(),
+            length: 8,
+            prefix: String::new(),
+            check_character: false
+        }
+    }
+}
+
+// 60 random bits, so that nobody can guess someone else's referral link
+pub const REFERRAL_TOKEN_LENGTH: usize = 12;
+
+// Referral tokens are matched like codes, whatever their case
+pub fn generate_referral_token<R: Rng>(rng: &mut R) -> String {
+    let alphabet: Vec<char> = READABLE_ALPHABET.chars().collect();
+    (0..REFERRAL_TOKEN_LENGTH)
+        .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+        .collect()
+}
+
+// What users type is matched whatever its case, spaces and dashes
+pub fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+impl CodeFormat {
+    const MIN_LENGTH: usize = 4;
+    const MAX_LENGTH: usize = 64;
+
+    // The same format written the way codes are stored, or why it can't be used
+    pub fn validated(self) -> RaffleResult<CodeFormat> {
+        let mut alphabet = String::new();
+        for c in normalize_code(&self.alphabet).chars() {
+            if !alphabet.contains(c) {
+                alphabet.push(c);
+            }
+        }
+        if alphabet.chars().count() < 2 {
+            return Err(RaffleDbError::ConstraintViolation(format!("the code alphabet {:?} needs at least two different characters", self.alphabet)));
+        }
+        if self.length < CodeFormat::MIN_LENGTH || self.length > CodeFormat::MAX_LENGTH {
+            return Err(RaffleDbError::ConstraintViolation(format!("codes must have between {} and {} characters, not {}",
+                CodeFormat::MIN_LENGTH, CodeFormat::MAX_LENGTH, self.length)));
+        }
+        Ok(CodeFormat {
+            alphabet,
+            length: self.length,
+            prefix: normalize_code(&self.prefix),
+            check_character: self.check_character
+        })
+    }
+
+    pub fn generate<R: Rng>(&self, rng: &mut R) -> String {
+        let alphabet: Vec<char> = self.alphabet.chars().collect();
+        let body: String = (0..self.length)
+            .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+            .collect();
+        let check = if self.check_character { self.check_character_of(&body) } else { None };
+        format!("{}{}{}", self.prefix, body, check.map(String::from).unwrap_or_default())
+    }
+
+    // True when the code has this format but its check character is wrong, so it can't exist
+    pub fn looks_mistyped(&self, code: &str) -> bool {
+        if !self.check_character {
+            return false;
+        }
+        let code = normalize_code(code);
+        let body: Vec<char> = match code.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => rest.chars().collect(),
+            None => return false
+        };
+        match body.split_last() {
+            Some((check, body)) if body.len() == self.length =>
+                self.check_character_of(&body.iter().collect::<String>()) != Some(*check),
+            _ => false
+        }
+    }
+
+    // Luhn mod N, None if a character is not in the alphabet
+    fn check_character_of(&self, body: &str) -> Option<char> {
+        let alphabet: Vec<char> = self.alphabet.chars().collect();
+        let base = alphabet.len();
+        let mut factor = 2;
+        let mut sum = 0;
+        for c in body.chars().rev() {
+            let addend = factor * alphabet.iter().position(|a| *a == c)?;
+            sum += addend / base + addend % base;
+            factor = if factor == 2 { 1 } else { 2 };
+        }
+        Some(alphabet[(base - sum % base) % base])
+    }
+}
@@ -0,0 +1,193 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::db::*;
+use crate::codes::{CodeFormat, READABLE_ALPHABET};
+use super::{priority_of, start_raffle};
+
+// Redeeming codes: how often, until when, in which format and by whom
+
+pub fn check_code_use_limits<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Code limits", DrawMode::Weighted);
+    for user_id in 1..=3 {
+        db.register_partecipant(raffle, user_id, None, None).unwrap();
+    }
+
+    let once = db.generate_raffle_code(raffle, CodeUseCount::Once, CodeOptions::default()).unwrap();
+    assert_eq!(once.raffle_id, raffle);
+    assert_eq!(once.remaining_uses, 1);
+    assert_eq!(db.validate_code(&once.code).unwrap(), CodeValidation::Valid(once.unique_id));
+    assert_eq!(db.redeem_code(1, once.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    assert!(matches!(db.validate_code(&once.code).unwrap(), CodeValidation::NotValid(_)));
+    assert_eq!(db.redeem_code(2, once.unique_id).unwrap(), CodeRedeemalResult::Exhausted);
+    assert_eq!(db.find_raffle_code(&once.code).unwrap().unwrap().remaining_uses, 0);
+
+    let counted = db.generate_raffle_code(raffle, CodeUseCount::Counted(2), CodeOptions::default()).unwrap();
+    assert_eq!(db.redeem_code(1, counted.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    assert_eq!(db.get_raffle_code_by_id(counted.unique_id).unwrap().unwrap().remaining_uses, 1);
+    assert_eq!(db.redeem_code(2, counted.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    assert_eq!(db.get_raffle_code_by_id(counted.unique_id).unwrap(), None);
+    assert_eq!(db.redeem_code(3, counted.unique_id).unwrap(), CodeRedeemalResult::Exhausted);
+    // A partecipant who got it in time is told so, rather than that it is used up
+    assert_eq!(db.redeem_code(1, counted.unique_id).unwrap(), CodeRedeemalResult::AlreadyRedeemed);
+
+    assert!(matches!(db.generate_raffle_code(raffle, CodeUseCount::Expired, CodeOptions::default()), Err(RaffleDbError::InvalidUseCount(_))));
+    assert!(matches!(db.generate_raffle_code(raffle, CodeUseCount::Counted(0), CodeOptions::default()), Err(RaffleDbError::InvalidUseCount(_))));
+
+    let illimited = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    for user_id in 1..=3 {
+        assert_eq!(db.redeem_code(user_id, illimited.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    }
+    assert_eq!(db.get_raffle_code_by_name(&illimited.code).unwrap().unwrap().remaining_uses, -1);
+
+    // A deleted code can't be redeemed but is still part of the raffle history
+    db.delete_raffle_code(illimited.unique_id).unwrap();
+    assert_eq!(db.get_raffle_code_by_id(illimited.unique_id).unwrap(), None);
+    assert!(db.find_raffle_code(&illimited.code).unwrap().unwrap().revoked_when.is_some());
+    db.register_partecipant(raffle, 4, None, None).unwrap();
+    assert_eq!(db.redeem_code(4, illimited.unique_id).unwrap(), CodeRedeemalResult::Exhausted);
+    assert_eq!(db.get_raffle_codes(raffle).unwrap().len(), 3);
+    assert_eq!(db.get_used_codes(raffle).unwrap().len(), 6);
+    assert_eq!(priority_of(db, raffle, 1), 4);
+    assert_eq!(priority_of(db, raffle, 3), 2);
+
+    // Codes die with their raffle
+    let leftover = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    db.stop_raffle(raffle, 1).unwrap();
+    assert_eq!(db.get_raffle_code_by_name(&leftover.code).unwrap(), None);
+    assert_eq!(db.find_raffle_code(&leftover.code).unwrap(), Some(leftover.clone()));
+    assert_eq!(db.redeem_code(1, leftover.unique_id).unwrap(), CodeRedeemalResult::NonExistingCode);
+    assert!(matches!(db.generate_raffle_code(raffle, CodeUseCount::Once, CodeOptions::default()), Err(RaffleDbError::NoOngoingRaffle(_))));
+}
+
+pub fn check_already_redeemed<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Already redeemed", DrawMode::Weighted);
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+    let code = db.generate_raffle_code(raffle, CodeUseCount::Counted(5), CodeOptions::default()).unwrap();
+
+    assert!(!db.partecipant_has_redeemed_code(1, code.unique_id).unwrap());
+    assert_eq!(db.redeem_code(1, code.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    assert!(db.partecipant_has_redeemed_code(1, code.unique_id).unwrap());
+    assert_eq!(db.redeem_code(1, code.unique_id).unwrap(), CodeRedeemalResult::AlreadyRedeemed);
+    // The second try neither counts as a use nor gives points
+    assert_eq!(db.get_raffle_code_by_id(code.unique_id).unwrap().unwrap().remaining_uses, 4);
+    assert_eq!(priority_of(db, raffle, 1), 2);
+    assert_eq!(db.get_raffle_codes_used_by_user(raffle, 1).unwrap().len(), 1);
+
+    // Only partecipants can redeem codes
+    assert_eq!(db.redeem_code(2, code.unique_id).unwrap(), CodeRedeemalResult::NonExistingUser);
+    assert_eq!(db.redeem_code(1, code.unique_id + 1000).unwrap(), CodeRedeemalResult::NonExistingCode);
+}
+
+pub fn check_code_expiry<DB: RaffleDB>(db: &mut DB) {
+    // The database reads this clock, so time moves only when the check says so
+    let clock = Arc::new(AtomicU64::new(1_000_000));
+    let now = Arc::clone(&clock);
+    db.set_clock(Box::new(move || now.load(Ordering::SeqCst)));
+    let raffle = start_raffle(db, "Expiry", DrawMode::Weighted);
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+    db.register_partecipant(raffle, 2, None, None).unwrap();
+    let expiring = CodeOptions { expires_when: Some(1_000_100), ..CodeOptions::default() };
+    let code = db.generate_raffle_code(raffle, CodeUseCount::Illimited, expiring).unwrap();
+    assert_eq!(code.use_count(1_000_000), CodeUseCount::Illimited);
+    assert_eq!(code.use_count(1_000_099), CodeUseCount::Illimited);
+    assert_eq!(code.use_count(1_000_100), CodeUseCount::Expired);
+    assert_eq!(db.redeem_code(1, code.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+
+    clock.store(1_000_100, Ordering::SeqCst);
+    assert_eq!(db.redeem_code(2, code.unique_id).unwrap(), CodeRedeemalResult::Expired);
+    assert_eq!(db.get_raffle_code_by_name(&code.code).unwrap(), None);
+    assert_eq!(db.get_raffle_code_by_id(code.unique_id).unwrap(), None);
+    assert!(db.find_raffle_code(&code.code).unwrap().is_some());
+    assert!(matches!(db.validate_code(&code.code).unwrap(), CodeValidation::NotValid(reason) if reason.contains("expired")));
+    assert_eq!(priority_of(db, raffle, 2), 1);
+
+    let already_expired = CodeOptions { expires_when: Some(1_000_100), ..CodeOptions::default() };
+    assert!(matches!(db.generate_raffle_code(raffle, CodeUseCount::Once, already_expired), Err(RaffleDbError::ConstraintViolation(_))));
+}
+
+pub fn check_code_format<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Code format", DrawMode::Weighted);
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+    let readable = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    assert_eq!(readable.code.len(), 8);
+    assert!(readable.code.chars().all(|c| READABLE_ALPHABET.contains(c)));
+
+    db.set_code_format(CodeFormat {
+        alphabet: "xyz w".to_owned(),
+        length: 6,
+        prefix: "live-".to_owned(),
+        check_character: true
+    }).unwrap();
+    let live = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    assert!(live.code.starts_with("LIVE"));
+    assert_eq!(live.code.len(), 4 + 6 + 1);
+    assert!(live.code[4..].chars().all(|c| "XYZW".contains(c)));
+
+    // Typed from a stream: lowercase, with spaces and dashes
+    let typed = format!(" {}-{} ", live.code[..5].to_lowercase(), &live.code[5..]);
+    assert_eq!(db.find_raffle_code(&typed).unwrap(), Some(live.clone()));
+    assert_eq!(db.get_raffle_code_by_name(&typed).unwrap(), Some(live.clone()));
+    assert_eq!(db.validate_code(&typed).unwrap(), CodeValidation::Valid(live.unique_id));
+    // Typos are judged with the validated format, the one the codes were normalized with
+    let format = db.get_code_format();
+    assert_eq!((format.alphabet.as_str(), format.prefix.as_str()), ("XYZW", "LIVE"));
+    assert!(!format.looks_mistyped(&typed));
+    let mut mistyped: Vec<char> = live.code.chars().collect();
+    mistyped[4] = "XYZW".chars().find(|c| *c != mistyped[4]).unwrap();
+    assert!(format.looks_mistyped(&mistyped.iter().collect::<String>().to_lowercase()));
+
+    // Only 16 codes fit this format, clashes happen and are retried
+    db.set_code_format(CodeFormat { alphabet: "AB".to_owned(), length: 4, prefix: String::new(), check_character: false }).unwrap();
+    let tiny = (0..5)
+        .map(|_| db.generate_raffle_code(raffle, CodeUseCount::Once, CodeOptions::default()).unwrap().code)
+        .collect::<std::collections::HashSet<_>>();
+    assert_eq!(tiny.len(), 5);
+
+    let single_character = CodeFormat { alphabet: "aA".to_owned(), ..CodeFormat::default() };
+    assert!(matches!(db.set_code_format(single_character), Err(RaffleDbError::ConstraintViolation(_))));
+    let too_short = CodeFormat { length: 2, ..CodeFormat::default() };
+    assert!(matches!(db.set_code_format(too_short), Err(RaffleDbError::ConstraintViolation(_))));
+}
+
+pub fn check_bulk_codes<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Bulk codes", DrawMode::Weighted);
+    let options = CodeOptions { points: Some(3), label: Some("Flyers".to_owned()), ..CodeOptions::default() };
+    let codes = db.generate_raffle_codes(raffle, 300, CodeUseCount::Counted(2), options).unwrap();
+    assert_eq!(codes.len(), 300);
+    assert_eq!(codes.iter().map(|code| &code.code).collect::<std::collections::HashSet<_>>().len(), 300);
+    assert!(codes.iter().all(|code| code.remaining_uses == 2 && code.points == Some(3) && code.label.as_deref() == Some("Flyers")));
+    // What is returned is what was stored
+    assert_eq!(db.get_raffle_codes(raffle).unwrap(), codes.iter().cloned().collect());
+    assert_eq!(db.find_raffle_code(&codes[150].code).unwrap(), Some(codes[150].clone()));
+
+    // A batch that can't be completed leaves nothing behind: only 16 codes fit this format
+    db.set_code_format(CodeFormat { alphabet: "AB".to_owned(), length: 4, prefix: String::new(), check_character: false }).unwrap();
+    assert!(matches!(db.generate_raffle_codes(raffle, 17, CodeUseCount::Once, CodeOptions::default()), Err(RaffleDbError::ConstraintViolation(_))));
+    assert_eq!(db.get_raffle_codes(raffle).unwrap().len(), 300);
+
+    assert!(matches!(db.generate_raffle_codes(raffle, 0, CodeUseCount::Once, CodeOptions::default()), Err(RaffleDbError::ConstraintViolation(_))));
+    assert!(matches!(db.generate_raffle_codes(raffle, 5, CodeUseCount::Counted(0), CodeOptions::default()), Err(RaffleDbError::InvalidUseCount(_))));
+}
+
+pub fn check_personal_codes<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Personal codes", DrawMode::Weighted);
+    for user_id in 1..=3 {
+        db.register_partecipant(raffle, user_id, None, None).unwrap();
+    }
+    let personal = CodeOptions { owners: vec![2, 1, 2], ..CodeOptions::default() };
+    let code = db.generate_raffle_code(raffle, CodeUseCount::Illimited, personal).unwrap();
+    assert_eq!(db.get_code_owners(code.unique_id).unwrap(), vec![1, 2]);
+    assert_eq!(db.redeem_code(3, code.unique_id).unwrap(), CodeRedeemalResult::NotAllowed);
+    assert_eq!(db.redeem_code(1, code.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    assert_eq!(db.redeem_code(2, code.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    assert_eq!(priority_of(db, raffle, 3), 1);
+    // The owner must still be in the raffle
+    assert_eq!(db.redeem_code(4, code.unique_id).unwrap(), CodeRedeemalResult::NotAllowed);
+    let for_outsider = CodeOptions { owners: vec![4], ..CodeOptions::default() };
+    let outsider_code = db.generate_raffle_code(raffle, CodeUseCount::Once, for_outsider).unwrap();
+    assert_eq!(db.redeem_code(4, outsider_code.unique_id).unwrap(), CodeRedeemalResult::NonExistingUser);
+
+    let public = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    assert!(db.get_code_owners(public.unique_id).unwrap().is_empty());
+    assert_eq!(db.redeem_code(3, public.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+}
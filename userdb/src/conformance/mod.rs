@@ -0,0 +1,61 @@
+use crate::db::*;
+
+mod codes;
+mod points;
+mod raffles;
+mod referrals;
+
+pub use codes::*;
+pub use points::*;
+pub use raffles::*;
+pub use referrals::*;
+
+/*
+The behaviour every RaffleDB backend must have, written against the trait only.
+A new backend proves it behaves like SQLiteInstance by passing run_all in its tests:
+each check gets a new empty database from make_db and panics on the first difference.
+*/
+pub fn run_all<DB: RaffleDB, F: FnMut() -> DB>(mut make_db: F) {
+    check_raffle_execution(&mut make_db());
+    check_registration(&mut make_db());
+    check_self_referral_rejected(&mut make_db());
+    check_code_use_limits(&mut make_db());
+    check_already_redeemed(&mut make_db());
+    check_remove_partecipant(&mut make_db());
+    check_stop_raffle_winners(&mut make_db());
+    check_point_ledger(&mut make_db());
+    check_adjust_points(&mut make_db());
+    check_priorities_match_ledger(&mut make_db());
+    check_scoring_rules(&mut make_db());
+    check_code_points(&mut make_db());
+    check_code_expiry(&mut make_db());
+    check_code_format(&mut make_db());
+    check_bulk_codes(&mut make_db());
+    check_personal_codes(&mut make_db());
+    check_campaigns(&mut make_db());
+    check_referral_tokens(&mut make_db());
+    check_referral_slugs(&mut make_db());
+    check_user_settings(&mut make_db());
+    check_referees(&mut make_db());
+}
+
+// Also used by the unit tests of the crate
+pub(crate) fn start_raffle<DB: RaffleDB>(db: &mut DB, name: &str, draw_mode: DrawMode) -> RaffleID {
+    match db.create_raffle(name, "Conformance description", draw_mode, ScoringRules::default()).unwrap() {
+        RaffleCreationResult::Success(raffle) => raffle.raffle_id,
+        other => panic!("Could not start the raffle {}: {:?}", name, other)
+    }
+}
+
+fn start_raffle_with_rules<DB: RaffleDB>(db: &mut DB, name: &str, rules: ScoringRules) -> RaffleID {
+    match db.create_raffle(name, "Conformance description", DrawMode::Weighted, rules).unwrap() {
+        RaffleCreationResult::Success(raffle) => raffle.raffle_id,
+        other => panic!("Could not start the raffle {}: {:?}", name, other)
+    }
+}
+
+fn priority_of<DB: RaffleDB>(db: &DB, raffle_id: RaffleID, user_id: UserID) -> usize {
+    db.get_partecipant(raffle_id, user_id).unwrap()
+        .unwrap_or_else(|| panic!("{} should be a partecipant of raffle {}", user_id, raffle_id))
+        .priority
+}
@@ -0,0 +1,165 @@
+use crate::db::*;
+use super::{priority_of, start_raffle, start_raffle_with_rules};
+
+// The point ledger and the priorities summed from it
+
+pub fn check_point_ledger<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Ledger", DrawMode::Weighted);
+    let sources = |db: &DB, user_id: UserID| Vec::from_iter(db.get_point_events(raffle, user_id).unwrap()
+        .into_iter()
+        .map(|event| (event.source, event.points)));
+    assert!(db.get_point_events(raffle, 1).unwrap().is_empty());
+
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+    db.register_partecipant(raffle, 2, Some(1), None).unwrap();
+    let code = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    db.redeem_code(1, code.unique_id).unwrap();
+    db.redeem_code(1, code.unique_id).unwrap();
+    assert_eq!(sources(db, 1), vec![
+        (PointSource::Join, 1),
+        (PointSource::Referral(2), 1),
+        (PointSource::Code(code.unique_id), 1),
+    ]);
+    assert_eq!(sources(db, 2), vec![(PointSource::Join, 1)]);
+    let events = db.get_point_events(raffle, 1).unwrap();
+    assert!(events.iter().all(|event| event.raffle_id == raffle && event.user_id == 1 && event.created_when > 0));
+    assert_eq!(priority_of(db, raffle, 1) as i64, events.iter().map(|event| event.points).sum::<i64>());
+
+    // Coming back after leaving doesn't give a second join point
+    db.remove_partecipant(raffle, 2).unwrap();
+    db.register_partecipant(raffle, 2, Some(1), None).unwrap();
+    assert_eq!(sources(db, 2), vec![(PointSource::Join, 1)]);
+    assert_eq!(priority_of(db, raffle, 1), 3);
+
+    // The ledger of a raffle is its own
+    let other = start_raffle(db, "Other ledger", DrawMode::Weighted);
+    db.register_partecipant(other, 1, None, None).unwrap();
+    assert_eq!(sources(db, 1).len(), 3);
+    assert_eq!(db.get_point_events(other, 1).unwrap().len(), 1);
+}
+
+pub fn check_adjust_points<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Adjustments", DrawMode::Weighted);
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+
+    let partecipant = db.adjust_points(raffle, 1, 5, 100, "Won the quiz").unwrap().unwrap();
+    assert_eq!(partecipant.priority, 6);
+    let partecipant = db.adjust_points(raffle, 1, -2, 101, "Spam").unwrap().unwrap();
+    assert_eq!(partecipant.priority, 4);
+    let last_event = db.get_point_events(raffle, 1).unwrap().pop().unwrap();
+    assert_eq!(last_event.points, -2);
+    assert_eq!(last_event.source, PointSource::Adjustment { admin: 101, reason: "Spam".to_owned() });
+
+    // Points never go below zero, but the ledger keeps every deduction
+    assert_eq!(db.adjust_points(raffle, 1, -10, 101, "Cheating").unwrap().unwrap().priority, 0);
+    assert_eq!(db.get_point_events(raffle, 1).unwrap().len(), 4);
+
+    assert_eq!(db.adjust_points(raffle, 2, 1, 100, "Not a partecipant").unwrap(), None);
+    assert!(matches!(db.adjust_points(raffle, 1, 0, 100, "Nothing"), Err(RaffleDbError::ConstraintViolation(_))));
+    assert!(matches!(db.adjust_points(raffle, 1, 1, 100, "  "), Err(RaffleDbError::ConstraintViolation(_))));
+    db.stop_raffle(raffle, 1).unwrap();
+    assert!(matches!(db.adjust_points(raffle, 1, 1, 100, "Too late"), Err(RaffleDbError::NoOngoingRaffle(_))));
+}
+
+// Priorities are summed from the ledger, so each must match the partecipant's own events whatever earned them
+pub fn check_priorities_match_ledger<DB: RaffleDB>(db: &mut DB) {
+    let rules = ScoringRules { join_points: 1, referral_points: 2, code_points: 3 };
+    let raffle = start_raffle_with_rules(db, "Mixed ledger", rules);
+    // The same users earn points in another raffle too, which must not leak into this one
+    let other = start_raffle(db, "Other mixed ledger", DrawMode::Weighted);
+    for user_id in 1..=12 {
+        db.register_partecipant(raffle, user_id, if user_id > 3 { Some(user_id % 3 + 1) } else { None }, None).unwrap();
+        db.register_partecipant(other, user_id, Some(1), None).unwrap();
+    }
+    let code = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    let bonus = db.generate_raffle_code(raffle, CodeUseCount::Counted(4), CodeOptions { points: Some(7), ..CodeOptions::default() }).unwrap();
+    let other_code = db.generate_raffle_code(other, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    for user_id in 1..=12 {
+        if user_id % 2 == 0 {
+            db.redeem_code(user_id, code.unique_id).unwrap();
+        }
+        if user_id % 3 == 0 {
+            db.redeem_code(user_id, bonus.unique_id).unwrap();
+        }
+        db.redeem_code(user_id, other_code.unique_id).unwrap();
+    }
+    db.adjust_points(raffle, 2, 4, 100, "Helped out").unwrap();
+    db.adjust_points(raffle, 5, -20, 100, "Spam").unwrap();
+    db.adjust_points(raffle, 7, -1, 100, "Late").unwrap();
+    db.remove_partecipant(raffle, 11).unwrap();
+
+    let partecipants = db.get_partecipants(raffle).unwrap();
+    assert_eq!(partecipants.len(), 11);
+    for partecipant in partecipants {
+        let total: i64 = db.get_point_events(raffle, partecipant.user_id).unwrap().iter().map(|event| event.points).sum();
+        assert_eq!(partecipant.priority as i64, total.max(0), "priority of {}", partecipant.user_id);
+        assert_eq!(priority_of(db, raffle, partecipant.user_id), partecipant.priority);
+    }
+    // Joining, three referees, a code and an adjustment
+    assert_eq!(priority_of(db, raffle, 2), 1 + 3 * 2 + 3 + 4);
+    // Joining and both codes
+    assert_eq!(priority_of(db, raffle, 12), 1 + 3 + 7);
+    assert_eq!(priority_of(db, raffle, 5), 0);
+}
+
+pub fn check_scoring_rules<DB: RaffleDB>(db: &mut DB) {
+    let rules = ScoringRules { join_points: 0, referral_points: 3, code_points: 5 };
+    let raffle = match db.create_raffle("Scoring", "Conformance description", DrawMode::Weighted, rules).unwrap() {
+        RaffleCreationResult::Success(raffle) => raffle,
+        other => panic!("Could not start the raffle: {:?}", other)
+    };
+    assert_eq!(raffle.scoring_rules, rules);
+    assert_eq!(db.get_raffle(raffle.raffle_id).unwrap().unwrap().scoring_rules, rules);
+    let raffle = raffle.raffle_id;
+
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+    assert_eq!(priority_of(db, raffle, 1), 0);
+    db.register_partecipant(raffle, 2, Some(1), None).unwrap();
+    assert_eq!(priority_of(db, raffle, 1), 3);
+    let code = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    db.redeem_code(2, code.unique_id).unwrap();
+    assert_eq!(priority_of(db, raffle, 2), 5);
+    // Even when it is worth nothing joining is in the ledger, so coming back gives nothing more
+    assert_eq!(db.get_point_events(raffle, 1).unwrap()[0].source, PointSource::Join);
+
+    // Rules only apply to their own raffle
+    let other = start_raffle(db, "Default scoring", DrawMode::Weighted);
+    db.register_partecipant(other, 1, None, None).unwrap();
+    assert_eq!(priority_of(db, other, 1), 1);
+
+    let negative = ScoringRules { code_points: -1, ..ScoringRules::default() };
+    assert!(matches!(db.create_raffle("Negative", "Conformance description", DrawMode::Weighted, negative),
+        Err(RaffleDbError::ConstraintViolation(_))));
+}
+
+pub fn check_code_points<DB: RaffleDB>(db: &mut DB) {
+    let rules = ScoringRules { code_points: 5, ..ScoringRules::default() };
+    let raffle = start_raffle_with_rules(db, "Code points", rules);
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+
+    let big = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions {
+        points: Some(20),
+        label: Some(" Live event ".to_owned()),
+        ..CodeOptions::default()
+    }).unwrap();
+    assert_eq!(big.points, Some(20));
+    assert_eq!(big.label.as_deref(), Some("Live event"));
+    assert_eq!(db.find_raffle_code(&big.code).unwrap().unwrap().label.as_deref(), Some("Live event"));
+    assert_eq!(db.redeem_code(1, big.unique_id).unwrap(), CodeRedeemalResult::Redeemed(20));
+    assert_eq!(priority_of(db, raffle, 1), 21);
+
+    // Without a value of its own a code is worth what the raffle says
+    let daily = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions {
+        points: None,
+        label: Some("  ".to_owned()),
+        ..CodeOptions::default()
+    }).unwrap();
+    assert_eq!(daily.label, None);
+    assert_eq!(db.redeem_code(1, daily.unique_id).unwrap(), CodeRedeemalResult::Redeemed(5));
+    assert_eq!(priority_of(db, raffle, 1), 26);
+    let events = db.get_point_events(raffle, 1).unwrap();
+    assert_eq!(events.iter().map(|event| event.points).collect::<Vec<_>>(), vec![1, 20, 5]);
+
+    let negative = CodeOptions { points: Some(-1), ..CodeOptions::default() };
+    assert!(matches!(db.generate_raffle_code(raffle, CodeUseCount::Once, negative), Err(RaffleDbError::ConstraintViolation(_))));
+}
@@ -0,0 +1,153 @@
+use crate::db::*;
+use super::{priority_of, start_raffle};
+
+// Raffles from start to end: joining, leaving and drawing the winners
+
+// A whole raffle from start to end, with referrals, codes and a partecipant leaving
+pub fn check_raffle_execution<DB: RaffleDB>(db: &mut DB) {
+    assert!(db.register_partecipant(1, 0, None, None).unwrap() == RegistrationStatus::NoRaffleOngoing);
+    let new_raffle = db.create_raffle("Test Raffle 2", "Test Description", DrawMode::Leaderboard, ScoringRules::default()).unwrap();
+    assert!(new_raffle.is_success());
+    let raffle = db.get_ongoing_raffles().unwrap()[0].raffle_id;
+    db.register_partecipant(raffle, 0, None, None).unwrap();
+    assert_eq!(db.get_referrer_of_user(raffle, 0).unwrap(), None);
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+    assert_eq!(db.get_referrer_of_user(raffle, 1).unwrap(), None);
+    db.register_partecipant(raffle, 2, Some(1), None).unwrap();
+    assert_eq!(db.get_referees_of_user(raffle, 1).unwrap().len(), 1);
+    assert_eq!(db.get_referrer_of_user(raffle, 2).unwrap().unwrap(), 1);
+
+    let current_partecipants = db.get_partecipants(raffle).unwrap();
+    assert_eq!(current_partecipants.len(), 3);
+
+    assert!(db.remove_partecipant(raffle, 0).unwrap());
+    let current_partecipants = db.get_partecipants(raffle).unwrap();
+    assert_eq!(current_partecipants.len(), 2);
+    assert!(!current_partecipants.iter().fold(false, |v, p| v || p.user_id == 0));
+    assert_eq!(db.get_registration_status(raffle, 0).unwrap(), RegistrationStatus::NotRegistered);
+    
+
+    let new_code = db.generate_raffle_code(raffle, CodeUseCount::Once, CodeOptions::default()).unwrap();
+    assert!(match db.validate_code(new_code.code.as_ref()).unwrap() {
+        CodeValidation::Valid(_) => true,
+        _ => false
+    });
+    db.redeem_code(1, new_code.unique_id).unwrap();
+    let validation_now = db.validate_code(new_code.code.as_ref()).unwrap();
+    assert!(match validation_now {
+        CodeValidation::Valid(_) => false,
+        _ => true
+    });
+
+    let new_code = db.generate_raffle_code(raffle, CodeUseCount::Counted(10), CodeOptions::default()).unwrap();
+    assert_eq!(db.redeem_code(1, new_code.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    assert_eq!(db.redeem_code(1, new_code.unique_id).unwrap(), CodeRedeemalResult::AlreadyRedeemed);
+    assert_eq!(db.redeem_code(2, new_code.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    
+    let usage_count = db.get_raffle_code_by_id(new_code.unique_id).unwrap().unwrap().remaining_uses;
+    assert_eq!(usage_count, 8);
+    db.delete_raffle_code(new_code.unique_id).unwrap();
+    let usage_count = db.get_raffle_code_by_id(new_code.unique_id).unwrap();
+    assert_eq!(usage_count, None);
+    
+
+    for i in 10..20 {
+        db.register_partecipant(raffle, i, Some(2), None).unwrap();
+    }
+    for i in 21..30 {
+        db.register_partecipant(raffle, i, None, None).unwrap();
+    }
+
+    let winners = db.stop_raffle(raffle, 3).unwrap().winners;
+    assert_eq!(winners.into_iter().next().unwrap().user_id, 2);
+
+    // The raffle history is kept after the raffle ends
+    assert!(db.get_ongoing_raffles().unwrap().is_empty());
+    assert!(db.get_raffle(raffle).unwrap().unwrap().ended_when.is_some());
+    assert_eq!(db.get_raffle_winners(raffle).unwrap(), vec![2, 1, 10]);
+    assert_eq!(db.get_partecipants(raffle).unwrap().len(), 21);
+    assert_eq!(db.get_referrals(raffle).unwrap().len(), 11);
+    assert_eq!(db.get_used_codes(raffle).unwrap().len(), 3);
+    assert_eq!(db.get_raffle_codes(raffle).unwrap().len(), 2);
+}
+
+pub fn check_registration<DB: RaffleDB>(db: &mut DB) {
+    assert_eq!(db.register_partecipant(1, 1, None, None).unwrap(), RegistrationStatus::NoRaffleOngoing);
+    let raffle = start_raffle(db, "Registration", DrawMode::Weighted);
+
+    match db.register_partecipant(raffle, 1, None, None).unwrap() {
+        RegistrationStatus::Registered(partecipant) => {
+            assert_eq!(partecipant.user_id, 1);
+            assert_eq!(partecipant.priority, 1);
+        },
+        other => panic!("Registration failed: {:?}", other)
+    }
+    assert!(db.is_partecipant(raffle, 1).unwrap());
+    // Registering twice changes nothing
+    assert_eq!(db.register_partecipant(raffle, 1, None, None).unwrap(), RegistrationStatus::NotRegistered);
+    assert_eq!(db.get_partecipants(raffle).unwrap().len(), 1);
+    assert!(matches!(db.get_registration_status(raffle, 1).unwrap(), RegistrationStatus::Registered(_)));
+    assert_eq!(db.get_registration_status(raffle, 2).unwrap(), RegistrationStatus::NotRegistered);
+    assert!(!db.is_partecipant(raffle, 2).unwrap());
+
+    db.stop_raffle(raffle, 1).unwrap();
+    assert_eq!(db.register_partecipant(raffle, 2, None, None).unwrap(), RegistrationStatus::NoRaffleOngoing);
+}
+
+pub fn check_remove_partecipant<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Leaving", DrawMode::Weighted);
+    assert!(!db.remove_partecipant(raffle, 1).unwrap());
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+    db.register_partecipant(raffle, 2, Some(1), None).unwrap();
+    let code = db.generate_raffle_code(raffle, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    db.redeem_code(1, code.unique_id).unwrap();
+
+    assert!(db.remove_partecipant(raffle, 1).unwrap());
+    assert!(!db.remove_partecipant(raffle, 1).unwrap());
+    assert!(!db.is_partecipant(raffle, 1).unwrap());
+    assert_eq!(db.get_partecipant(raffle, 1).unwrap(), None);
+    assert_eq!(db.get_registration_status(raffle, 1).unwrap(), RegistrationStatus::NotRegistered);
+    assert_eq!(db.get_partecipants(raffle).unwrap().len(), 1);
+    assert_eq!(db.redeem_code(1, code.unique_id).unwrap(), CodeRedeemalResult::NonExistingUser);
+
+    // Whoever comes back finds the points they had
+    assert!(matches!(db.register_partecipant(raffle, 1, None, None).unwrap(), RegistrationStatus::Registered(_)));
+    assert_eq!(priority_of(db, raffle, 1), 3);
+    assert_eq!(db.get_partecipants(raffle).unwrap().len(), 2);
+}
+
+pub fn check_stop_raffle_winners<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Winners", DrawMode::Leaderboard);
+    let other = start_raffle(db, "Other winners", DrawMode::Weighted);
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+    db.register_partecipant(raffle, 2, Some(1), None).unwrap();
+    db.register_partecipant(raffle, 3, Some(1), None).unwrap();
+    db.register_partecipant(raffle, 4, Some(3), None).unwrap();
+    db.register_partecipant(other, 1, None, None).unwrap();
+    let partecipants_before = db.get_partecipants(raffle).unwrap();
+
+    let outcome = db.stop_raffle(raffle, 10).unwrap();
+    let winners = Vec::from_iter(outcome.winners.iter().map(|winner| winner.user_id));
+    assert_eq!(winners.len(), 4);
+    assert_eq!(&winners[..2], &[1, 3]);
+    assert_eq!(db.get_raffle_winners(raffle).unwrap(), winners);
+    assert_eq!(outcome.partecipants_hash, crate::draw::partecipants_hash(&Vec::from_iter(partecipants_before.into_iter())));
+    let ended = db.get_raffle(raffle).unwrap().unwrap();
+    assert!(ended.ended_when.is_some());
+    assert_eq!(ended.seed_hash, Some(crate::draw::seed_hash(&outcome.seed)));
+
+    // A raffle ends only once, and the others keep running
+    assert!(matches!(db.stop_raffle(raffle, 1), Err(RaffleDbError::NoOngoingRaffle(_))));
+    assert!(matches!(db.stop_raffle(raffle + 1000, 1), Err(RaffleDbError::RaffleNotFound(_))));
+    assert_eq!(db.get_raffle_winners(raffle).unwrap(), winners);
+    assert_eq!(db.get_ongoing_raffles().unwrap().len(), 1);
+    assert!(db.get_raffle_winners(other).unwrap().is_empty());
+    assert_eq!(db.stop_raffle(other, 1).unwrap().winners.len(), 1);
+    assert_eq!(db.get_raffle_winners(other).unwrap(), vec![1]);
+
+    // Without partecipants there is no winner, but the raffle still ends
+    let empty = start_raffle(db, "Nobody", DrawMode::Weighted);
+    assert!(db.stop_raffle(empty, 3).unwrap().winners.is_empty());
+    assert!(db.get_raffle_winners(empty).unwrap().is_empty());
+    assert!(db.get_ongoing_raffles().unwrap().is_empty());
+}
@@ -0,0 +1,136 @@
+use crate::db::*;
+use crate::codes::{READABLE_ALPHABET, REFERRAL_TOKEN_LENGTH};
+use super::{priority_of, start_raffle, start_raffle_with_rules};
+
+// Referrals, the links they come through and what referrers see of them
+
+pub fn check_self_referral_rejected<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Referrals", DrawMode::Weighted);
+    assert!(matches!(db.register_partecipant(raffle, 1, Some(1), None).unwrap(), RegistrationStatus::Registered(_)));
+    assert_eq!(db.get_referrer_of_user(raffle, 1).unwrap(), None);
+    assert_eq!(priority_of(db, raffle, 1), 1);
+
+    // Only partecipants can refer someone
+    assert!(matches!(db.register_partecipant(raffle, 2, Some(3), None).unwrap(), RegistrationStatus::Registered(_)));
+    assert_eq!(db.get_referrer_of_user(raffle, 2).unwrap(), None);
+
+    assert!(matches!(db.register_partecipant(raffle, 3, Some(1), None).unwrap(), RegistrationStatus::Referred(_, 1)));
+    assert_eq!(db.get_referrer_of_user(raffle, 3).unwrap(), Some(1));
+    assert_eq!(db.get_referees_of_user(raffle, 1).unwrap(), vec![3]);
+    assert_eq!(priority_of(db, raffle, 1), 2);
+
+    // Leaving and joining again through another link doesn't give a second referral
+    assert!(db.remove_partecipant(raffle, 3).unwrap());
+    assert!(matches!(db.register_partecipant(raffle, 3, Some(2), None).unwrap(), RegistrationStatus::Registered(_)));
+    assert_eq!(db.get_referrer_of_user(raffle, 3).unwrap(), Some(1));
+    assert!(db.get_referees_of_user(raffle, 2).unwrap().is_empty());
+    assert_eq!(db.get_referrals(raffle).unwrap(), vec![Referral { referrer: 1, referee: 3 }]);
+}
+
+pub fn check_campaigns<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Campaigns", DrawMode::Weighted);
+    db.register_partecipant(raffle, 1, None, Some("IG_Story")).unwrap();
+    db.register_partecipant(raffle, 2, Some(1), Some("ig_story")).unwrap();
+    db.register_partecipant(raffle, 3, None, Some("poster_milan")).unwrap();
+    db.register_partecipant(raffle, 4, None, None).unwrap();
+    db.register_partecipant(raffle, 5, None, None).unwrap();
+    // Leaving and coming back through another link doesn't change where the partecipant came from
+    db.remove_partecipant(raffle, 3).unwrap();
+    db.register_partecipant(raffle, 3, None, Some("ig_story")).unwrap();
+    db.remove_partecipant(raffle, 2).unwrap();
+    let campaign = |name: Option<&str>, joins, partecipants| CampaignJoins { campaign: name.map(str::to_owned), joins, partecipants };
+    assert_eq!(db.get_campaign_joins(raffle).unwrap(), vec![
+        campaign(Some("ig_story"), 2, 1),
+        campaign(None, 2, 2),
+        campaign(Some("poster_milan"), 1, 1),
+    ]);
+    assert!(matches!(db.register_partecipant(raffle, 6, None, Some("poster-milan")), Err(RaffleDbError::ConstraintViolation(_))));
+    assert!(matches!(db.register_partecipant(raffle, 6, None, Some("")), Err(RaffleDbError::ConstraintViolation(_))));
+    assert!(!db.is_partecipant(raffle, 6).unwrap());
+    let other = start_raffle(db, "No campaigns", DrawMode::Weighted);
+    assert!(db.get_campaign_joins(other).unwrap().is_empty());
+}
+
+pub fn check_referral_tokens<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Referral tokens", DrawMode::Weighted);
+    let other = start_raffle(db, "Other referral tokens", DrawMode::Weighted);
+    assert_eq!(db.get_referral_token(raffle, 1).unwrap(), None);
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+    db.register_partecipant(other, 1, None, None).unwrap();
+    let token = db.get_referral_token(raffle, 1).unwrap().unwrap();
+    assert_eq!((token.raffle_id, token.user_id), (raffle, 1));
+    assert_eq!(token.token.len(), REFERRAL_TOKEN_LENGTH);
+    assert!(token.token.chars().all(|c| READABLE_ALPHABET.contains(c)));
+    // Asking again gives the same token, each raffle has its own
+    assert_eq!(db.get_referral_token(raffle, 1).unwrap(), Some(token.clone()));
+    let other_token = db.get_referral_token(other, 1).unwrap().unwrap();
+    assert_ne!(other_token.token, token.token);
+
+    assert_eq!(db.find_referral_token(&token.token.to_lowercase()).unwrap(), Some(token.clone()));
+    assert_eq!(db.find_referral_token("NOTATOKEN").unwrap(), None);
+    // Leaving doesn't invalidate the token, but only partecipants can refer
+    db.remove_partecipant(raffle, 1).unwrap();
+    assert_eq!(db.find_referral_token(&token.token).unwrap(), Some(token));
+    assert_eq!(db.get_referral_token(raffle, 1).unwrap(), None);
+}
+
+pub fn check_referral_slugs<DB: RaffleDB>(db: &mut DB) {
+    assert_eq!(db.claim_referral_slug(1, " Marco ").unwrap(), SlugClaimResult::Claimed("marco".to_owned()));
+    assert_eq!(db.claim_referral_slug(1, "marco").unwrap(), SlugClaimResult::Claimed("marco".to_owned()));
+    assert_eq!(db.claim_referral_slug(2, "MARCO").unwrap(), SlugClaimResult::Taken);
+    assert_eq!(db.find_referral_slug("Marco").unwrap(), Some(1));
+    assert_eq!(db.get_referral_slug(1).unwrap(), Some("marco".to_owned()));
+    assert_eq!(db.get_referral_slug(2).unwrap(), None);
+
+    for invalid in ["ab", "1marco", "marco-rossi", "marco rossi", "", "a_very_long_slug_that_goes_on"].iter() {
+        assert_eq!(db.claim_referral_slug(2, invalid).unwrap(), SlugClaimResult::Invalid, "{:?}", invalid);
+    }
+    assert_eq!(db.claim_referral_slug(2, "Admin").unwrap(), SlugClaimResult::Reserved);
+    assert_eq!(db.find_referral_slug("admin").unwrap(), None);
+
+    // A new slug frees the previous one
+    assert_eq!(db.claim_referral_slug(1, "marco_rossi").unwrap(), SlugClaimResult::Claimed("marco_rossi".to_owned()));
+    assert_eq!(db.find_referral_slug("marco").unwrap(), None);
+    assert_eq!(db.claim_referral_slug(2, "marco").unwrap(), SlugClaimResult::Claimed("marco".to_owned()));
+    assert_eq!(db.find_referral_slug("marco").unwrap(), Some(2));
+    assert_eq!(db.find_referral_slug("marco_rossi").unwrap(), Some(1));
+}
+
+pub fn check_user_settings<DB: RaffleDB>(db: &mut DB) {
+    assert_eq!(db.get_user_settings(1).unwrap(), UserSettings::default());
+    assert!(db.get_user_settings(1).unwrap().referral_notifications);
+    db.set_user_settings(1, UserSettings { referral_notifications: false }).unwrap();
+    assert!(!db.get_user_settings(1).unwrap().referral_notifications);
+    assert!(db.get_user_settings(2).unwrap().referral_notifications);
+    db.set_user_settings(1, UserSettings { referral_notifications: true }).unwrap();
+    assert!(db.get_user_settings(1).unwrap().referral_notifications);
+}
+
+pub fn check_referees<DB: RaffleDB>(db: &mut DB) {
+    let raffle = start_raffle(db, "Referees", DrawMode::Weighted);
+    let rules = ScoringRules { join_points: 1, referral_points: 3, code_points: 1 };
+    let generous = start_raffle_with_rules(db, "Generous referees", rules);
+    assert!(db.get_referees(raffle, 1).unwrap().is_empty());
+    db.register_partecipant(raffle, 1, None, None).unwrap();
+    db.register_partecipant(raffle, 2, Some(1), None).unwrap();
+    db.register_partecipant(raffle, 3, Some(1), None).unwrap();
+    db.register_partecipant(raffle, 4, Some(2), None).unwrap();
+    db.register_partecipant(generous, 1, None, None).unwrap();
+    db.register_partecipant(generous, 5, Some(1), None).unwrap();
+    db.remove_partecipant(raffle, 3).unwrap();
+
+    let referees = db.get_referees(raffle, 1).unwrap();
+    assert_eq!(referees.iter().map(|referee| referee.user_id).collect::<Vec<_>>(), vec![2, 3]);
+    assert!(referees.iter().all(|referee| referee.points == 1 && referee.joined_when > 0));
+    assert_eq!(referees[0].left_when, None);
+    assert!(referees[1].left_when.is_some());
+    // Each raffle has its own referees and points
+    let generous_referees = db.get_referees(generous, 1).unwrap();
+    assert_eq!(generous_referees.iter().map(|referee| (referee.user_id, referee.points)).collect::<Vec<_>>(), vec![(5, 3)]);
+    assert_eq!(db.get_referees(raffle, 2).unwrap().iter().map(|referee| referee.user_id).collect::<Vec<_>>(), vec![4]);
+
+    // Coming back clears left_when, and gives the referrer nothing more
+    db.register_partecipant(raffle, 3, Some(1), None).unwrap();
+    let referees = db.get_referees(raffle, 1).unwrap();
+    assert!(referees.iter().all(|referee| referee.left_when.is_none() && referee.points == 1));
+}
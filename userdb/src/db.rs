@@ -1,16 +1,62 @@
-use std::{collections::HashSet, hash::Hash};
+use std::{collections::HashSet, fmt::Display, hash::Hash};
+use crate::db_instances::migrations::MigrationError;
+use crate::draw::DrawSeed;
+use crate::codes::CodeFormat;
 
 pub type UserID = i64;
 pub type RaffleID = u64;
 pub type Timestamp = u64;
+// Where a RaffleDB reads the time from, the system clock unless set otherwise
+pub type Clock = Box<dyn Fn() -> Timestamp + Send>;
 pub type RedeemableCodeId = u64;
-pub type RaffleResult<T> = std::result::Result<T, Box<dyn std::error::Error + Sync + Send>>;
+pub type RaffleResult<T> = std::result::Result<T, RaffleDbError>;
+
+#[derive(Debug)]
+pub enum RaffleDbError {
+    RaffleNotFound(RaffleID),
+    NoOngoingRaffle(RaffleID), // The raffle exists but has already ended
+    NotFound(String), // Something the database should have, but doesn't
+    ConstraintViolation(String), // e.g. a code that exists already
+    InvalidUseCount(CodeUseCount),
+    Migration(MigrationError),
+    Storage(Box<dyn std::error::Error + Sync + Send>), // The backend itself failed
+}
+
+impl Display for RaffleDbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RaffleDbError::RaffleNotFound(raffle_id) => write!(f, "There is no raffle {}", raffle_id),
+            RaffleDbError::NoOngoingRaffle(raffle_id) => write!(f, "The raffle {} is not running", raffle_id),
+            RaffleDbError::NotFound(what) => write!(f, "Not found: {}", what),
+            RaffleDbError::ConstraintViolation(reason) => write!(f, "Constraint violation: {}", reason),
+            RaffleDbError::InvalidUseCount(use_count) => write!(f, "A code can't be generated with use count {:?}", use_count),
+            RaffleDbError::Migration(cause) => write!(f, "{}", cause),
+            RaffleDbError::Storage(cause) => write!(f, "Storage failure: {}", cause),
+        }
+    }
+}
+
+impl std::error::Error for RaffleDbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RaffleDbError::Migration(cause) => Some(cause),
+            RaffleDbError::Storage(cause) => Some(cause.as_ref()),
+            _ => None
+        }
+    }
+}
+
+impl From<MigrationError> for RaffleDbError {
+    fn from(cause: MigrationError) -> Self {
+        RaffleDbError::Migration(cause)
+    }
+}
 
 #[derive(Debug, Eq, Clone)]
 pub struct Partecipant {
     pub user_id: UserID,
     pub joined_when: Timestamp,
-    pub priority: usize, // 1 + #codes used + 1 for each referred user
+    pub priority: usize, // The sum of the partecipant's point events, never below 0
 }
 
 impl PartialEq for Partecipant {
@@ -24,18 +70,40 @@ impl Hash for Partecipant {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Referral {
     pub referrer: UserID,
     pub referee: UserID,
 }
 
-#[derive(Debug, Eq)]
+#[derive(Debug, Eq, Clone)]
 pub struct RedeemableCode {
     pub code: String,
     pub unique_id: RedeemableCodeId,
+    pub raffle_id: RaffleID,
     pub remaining_uses: i32,
     pub generated_when: Timestamp,
+    pub points: Option<i64>, // None when the code is worth the raffle's code_points
+    pub label: Option<String>, // Where the code was handed out, e.g. a live event
+    pub expires_when: Option<Timestamp>, // From this moment on the code can't be redeemed
+    pub revoked_when: Option<Timestamp>, // When an admin stopped the code from being redeemed
+}
+
+impl RedeemableCode {
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expires_when.is_some_and(|expires_when| now >= expires_when)
+    }
+
+    // How many more times the code can be used, Expired once its time is up whatever its uses
+    pub fn use_count(&self, now: Timestamp) -> CodeUseCount {
+        if self.is_expired(now) {
+            CodeUseCount::Expired
+        } else if self.remaining_uses == -1 {
+            CodeUseCount::Illimited
+        } else {
+            CodeUseCount::Counted(self.remaining_uses)
+        }
+    }
 }
 
 impl PartialEq for RedeemableCode {
@@ -48,24 +116,124 @@ impl Hash for RedeemableCode {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.code.hash(state);
         self.unique_id.hash(state);
+        self.raffle_id.hash(state);
         self.remaining_uses.hash(state);
         self.generated_when.hash(state);
+        self.points.hash(state);
+        self.label.hash(state);
+        self.expires_when.hash(state);
     }
 }
 
-#[derive(Debug)]
+// What makes a code different from the others of its raffle, besides how many times it can be used
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CodeOptions {
+    pub points: Option<i64>, // None to use the raffle's code_points
+    pub label: Option<String>,
+    pub expires_when: Option<Timestamp>, // None for a code that never expires
+    pub owners: Vec<UserID>, // Only these users can redeem the code, anyone can when empty
+}
+
+// Someone who joined a raffle through a partecipant's link
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Referee {
+    pub user_id: UserID,
+    pub joined_when: Timestamp, // The last time they joined
+    pub left_when: Option<Timestamp>, // None while they are in the raffle
+    pub points: i64, // What the referrer got for them
+}
+
+// Stands for a partecipant in their referral links, so that the links don't reveal their user id
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ReferralToken {
+    pub token: String,
+    pub raffle_id: RaffleID,
+    pub user_id: UserID,
+    pub created_when: Timestamp,
+}
+
+// How many partecipants the links of a campaign, e.g. ig_story, brought to a raffle
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CampaignJoins {
+    pub campaign: Option<String>, // None for the partecipants who joined without a campaign link
+    pub joins: usize,
+    pub partecipants: usize, // The ones who joined and did not leave
+}
+
+#[derive(Debug, Clone)]
 pub struct UsedCode {
     pub partecpiant_user_id: UserID,
     pub code: String,
     pub used_when: Timestamp,
 }
 
-#[derive(Debug)]
+// Why a partecipant got, or lost, some points
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PointSource {
+    Join,
+    Referral(UserID), // The referee who joined through the partecipant's link
+    Code(RedeemableCodeId),
+    Adjustment { admin: UserID, reason: String }, // Points given or taken by hand
+}
+
+// One row of the points ledger, a partecipant's priority is the sum of their events
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PointEvent {
+    pub raffle_id: RaffleID,
+    pub user_id: UserID,
+    pub points: i64,
+    pub source: PointSource,
+    pub created_when: Timestamp,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DrawMode {
+    Weighted, // Each point is a ticket, winners are drawn without replacement
+    Leaderboard, // The partecipants with the most points win
+}
+
+// How many points a partecipant gets for each thing they do in a raffle
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ScoringRules {
+    pub join_points: i64,
+    pub referral_points: i64, // Given to the referrer
+    pub code_points: i64,
+}
+
+impl Default for ScoringRules {
+    fn default() -> Self {
+        ScoringRules {
+            join_points: 1,
+            referral_points: 1,
+            code_points: 1
+        }
+    }
+}
+
+// What each user chose for themselves, the same in every raffle
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct UserSettings {
+    pub referral_notifications: bool, // A message whenever someone joins through the user's link
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        UserSettings {
+            referral_notifications: true
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Raffle {
     pub raffle_id: RaffleID,
     pub raffle_name: String,
     pub raffle_description: String, // something else
     pub started_when: Timestamp,
+    pub ended_when: Option<Timestamp>,
+    pub draw_mode: DrawMode,
+    pub seed_hash: Option<String>, // SHA-256 of the secret draw seed, published when the raffle starts
+    pub scoring_rules: ScoringRules,
 }
 impl PartialEq for Raffle {
     fn eq(&self, other: &Self) -> bool {
@@ -73,9 +241,18 @@ impl PartialEq for Raffle {
     } 
 }
 
+// What stop_raffle reveals, everything needed to reproduce the draw
+#[derive(Debug)]
+pub struct RaffleOutcome {
+    pub winners: Vec<Partecipant>,
+    pub seed: DrawSeed,
+    pub partecipants_hash: String,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum RegistrationStatus {
     Registered(Partecipant),
+    Referred(Partecipant, UserID), // Registered, and the referral of the UserID was recorded now
     NoRaffleOngoing,
     NotRegistered
 }
@@ -84,7 +261,7 @@ pub enum RegistrationStatus {
 pub enum CodeUseCount {
     Counted(i32), // Code can be used a max of n times,
     Once, // = Counted(1),
-    Expired,
+    Expired, // Past its expiry, describes a code but can't be used to generate one
     Illimited,
     CodeNotValid,
 }
@@ -96,15 +273,25 @@ pub enum CodeValidation {
 
 #[derive(Debug, PartialEq)]
 pub enum CodeRedeemalResult {
-    Redeemed,
+    Redeemed(i64), // How many points the partecipant gained
     AlreadyRedeemed,
     NonExistingUser,
-    NonExistingCode
+    NonExistingCode,
+    Exhausted, // The code exists but it has no uses left
+    Expired, // The code exists but its time is up
+    NotAllowed, // The code is personal, and belongs to someone else
+}
+#[derive(Debug, PartialEq)]
+pub enum SlugClaimResult {
+    Claimed(String), // The slug as stored, the user's previous one is free again
+    Taken, // Another user has it
+    Reserved, // Could be mistaken for the bot or its staff
+    Invalid, // Too short, too long or with characters that don't fit in a link
 }
 #[derive(Debug, PartialEq)]
 pub enum RaffleCreationResult {
     Success(Raffle),
-    OngoingRaffleExists(Raffle)
+    OngoingRaffleExists(Raffle) // An ongoing raffle already has the same name
 }
 
 impl RaffleCreationResult {
@@ -117,33 +304,68 @@ impl RaffleCreationResult {
 }
 
 pub trait RaffleDB {
-    fn close(self) -> RaffleResult<()>;
+    fn close(self) -> RaffleResult<()> where Self: Sized;
+    fn set_clock(&mut self, clock: Clock); // Every time read from now on comes from it, e.g. to test expiry without waiting
     
     // raffle functions
-    fn create_raffle(&mut self, name: &str, description: &str) -> RaffleResult<RaffleCreationResult>;
-    fn get_ongoing_raffle(&self) -> RaffleResult<Option<Raffle>>;
-    fn stop_raffle(&mut self, num_winners: usize) -> RaffleResult<Vec<Partecipant>>;
+    // The draw seed is committed now, drawn from the backend's rng: a backend made with create_with_seed gives reproducible raffles
+    fn create_raffle(&mut self, name: &str, description: &str, draw_mode: DrawMode, scoring_rules: ScoringRules) -> RaffleResult<RaffleCreationResult>;
+    fn get_ongoing_raffles(&self) -> RaffleResult<Vec<Raffle>>; // Oldest first
+    fn get_raffle(&self, raffle_id: RaffleID) -> RaffleResult<Option<Raffle>>;
+    fn get_raffles(&self) -> RaffleResult<Vec<Raffle>>; // Every raffle ever started, oldest first
+    fn stop_raffle(&mut self, raffle_id: RaffleID, num_winners: usize) -> RaffleResult<RaffleOutcome>; // On error the raffle is left untouched
+    fn get_raffle_winners(&self, raffle_id: RaffleID) -> RaffleResult<Vec<UserID>>; // Sorted by position
 
     // user functions
-    fn get_partecipants(&self) -> RaffleResult<HashSet<Partecipant>>;
-    fn get_partecipant(&self, user_id: UserID) -> RaffleResult<Option<Partecipant>>;
-    fn is_partecipant(&self, user_id: UserID) -> RaffleResult<bool>;
-    fn register_partecipant(&mut self, user_id: UserID, referrer: Option<UserID>) -> RaffleResult<RegistrationStatus>;
-    fn remove_partecipant(&mut self, user_id: UserID) -> RaffleResult<bool>;
-    fn get_registration_status(&self, user_id: UserID) -> RaffleResult<RegistrationStatus>;
-    fn get_referees_of_user(&self, user_id: UserID) -> RaffleResult<Vec<UserID>>;
-    fn get_referrer_of_user(&self, user_id: UserID) -> RaffleResult<Option<UserID>>;
+    fn get_partecipants(&self, raffle_id: RaffleID) -> RaffleResult<HashSet<Partecipant>>;
+    fn get_partecipant(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Option<Partecipant>>;
+    fn is_partecipant(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<bool>;
+    /*
+    A partecipant who left is registered again, keeping the campaign they first came from.
+    The join point is given only once, coming back after leaving doesn't give another one.
+    */
+    fn register_partecipant(&mut self, raffle_id: RaffleID, user_id: UserID, referrer: Option<UserID>, campaign: Option<&str>) -> RaffleResult<RegistrationStatus>;
+    fn remove_partecipant(&mut self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<bool>;
+    fn get_registration_status(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<RegistrationStatus>;
+    fn get_referees_of_user(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Vec<UserID>>;
+    fn get_referrer_of_user(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Option<UserID>>;
+    fn get_referees(&self, raffle_id: RaffleID, referrer: UserID) -> RaffleResult<Vec<Referee>>; // Oldest join first, even those who left
+    fn get_referrals(&self, raffle_id: RaffleID) -> RaffleResult<Vec<Referral>>;
+    // Always the same token for a partecipant, created the first time; None if the user is not a partecipant
+    fn get_referral_token(&mut self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Option<ReferralToken>>;
+    fn find_referral_token(&self, token: &str) -> RaffleResult<Option<ReferralToken>>; // Matched like codes, whatever the case
+    // Vanity slugs are chosen by their users and, unlike tokens, are the same in every raffle
+    fn claim_referral_slug(&mut self, user_id: UserID, slug: &str) -> RaffleResult<SlugClaimResult>;
+    fn get_referral_slug(&self, user_id: UserID) -> RaffleResult<Option<String>>;
+    fn find_referral_slug(&self, slug: &str) -> RaffleResult<Option<UserID>>; // Whatever the case
+    fn get_user_settings(&self, user_id: UserID) -> RaffleResult<UserSettings>; // The defaults for users who never changed them
+    fn set_user_settings(&mut self, user_id: UserID, settings: UserSettings) -> RaffleResult<()>;
+    fn get_point_events(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Vec<PointEvent>>; // Oldest first
+    fn get_campaign_joins(&self, raffle_id: RaffleID) -> RaffleResult<Vec<CampaignJoins>>; // Most joins first
+    // Gives (or takes, when negative) points by hand, None when the user is not a partecipant of the raffle
+    fn adjust_points(&mut self, raffle_id: RaffleID, user_id: UserID, points: i64, admin: UserID, reason: &str) -> RaffleResult<Option<Partecipant>>;
 
     // raffle codes functions
-    fn generate_raffle_code(&mut self, use_count: CodeUseCount) -> RaffleResult<RedeemableCode>;
-    fn get_raffle_codes(&self) -> RaffleResult<HashSet<RedeemableCode>>;
-    fn get_raffle_codes_used_by_user(&self, user_id: UserID) -> RaffleResult<HashSet<RedeemableCodeId>>;
+    fn set_code_format(&mut self, format: CodeFormat) -> RaffleResult<()>; // Only for the codes generated from now on
+    fn get_code_format(&self) -> CodeFormat; // As validated by set_code_format, so it matches normalized codes
+    fn generate_raffle_code(&mut self, raffle_id: RaffleID, use_count: CodeUseCount, options: CodeOptions) -> RaffleResult<RedeemableCode>;
+    // count codes with the same settings, either all of them are generated or none is
+    fn generate_raffle_codes(&mut self, raffle_id: RaffleID, count: usize, use_count: CodeUseCount, options: CodeOptions) -> RaffleResult<Vec<RedeemableCode>>;
+    fn get_raffle_codes(&self, raffle_id: RaffleID) -> RaffleResult<HashSet<RedeemableCode>>;
+    fn get_raffle_codes_used_by_user(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<HashSet<RedeemableCodeId>>;
+    fn get_used_codes(&self, raffle_id: RaffleID) -> RaffleResult<Vec<UsedCode>>;
+    // Codes are looked up by name whatever their case, spaces and dashes
+    // The two functions below only return codes that can still be redeemed: not used up, not expired and in an ongoing raffle
     fn get_raffle_code_by_name(&self, name: &str) -> RaffleResult<Option<RedeemableCode>>;
     fn get_raffle_code_by_id(&self, code: RedeemableCodeId) -> RaffleResult<Option<RedeemableCode>>;
+    fn find_raffle_code(&self, name: &str) -> RaffleResult<Option<RedeemableCode>>; // Even if it is used up, expired or its raffle ended
     fn partecipant_has_redeemed_code(&self, partecipant_id: UserID, code_id: RedeemableCodeId) -> RaffleResult<bool>;
+    fn get_code_owners(&self, code_id: RedeemableCodeId) -> RaffleResult<Vec<UserID>>; // Empty when anyone can redeem the code
+    // The code can't be redeemed anymore and remembers when it was revoked, it stays in the raffle history
     fn delete_raffle_code(&mut self, code: RedeemableCodeId) -> RaffleResult<()>;
 
     fn validate_code(&self, code: &str) -> RaffleResult<CodeValidation>;
+    // Atomic: two partecipants racing for the last use of a code can't both get it
     fn redeem_code(&mut self, user_id: UserID, code_id: RedeemableCodeId) -> RaffleResult<CodeRedeemalResult>;
     
-}
\ No newline at end of file
+}
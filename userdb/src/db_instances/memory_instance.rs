@@ -0,0 +1,528 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use crate::db::*;
+use crate::db_instances::{CODE_GENERATION_ATTEMPTS, EXPIRED_CODE, numeric_usages, timestamp_now, validate_adjustment, validate_batch_size, validate_campaign, validate_slug, validate_code_options, validate_scoring_rules};
+use crate::codes::{CodeFormat, generate_referral_token, normalize_code};
+use crate::draw::{DrawSeed, draw_from_seed, generate_seed, partecipants_hash, seed_hash};
+
+/*
+A RaffleDB which lives only in memory, with the same semantics as SQLiteInstance.
+Everything is lost when it is dropped: use it for tests, demos and dry runs.
+*/
+pub struct MemoryInstance {
+    raffles: BTreeMap<RaffleID, StoredRaffle>,
+    partecipants: BTreeMap<(RaffleID, UserID), StoredPartecipant>,
+    codes: BTreeMap<RedeemableCodeId, RedeemableCode>,
+    code_owners: HashMap<RedeemableCodeId, Vec<UserID>>, // Only personal codes are here
+    used_codes: Vec<StoredUsedCode>,
+    referrals: Vec<(RaffleID, Referral)>,
+    referral_tokens: BTreeMap<String, ReferralToken>,
+    referral_slugs: BTreeMap<String, UserID>,
+    user_settings: HashMap<UserID, UserSettings>,
+    winners: BTreeMap<RaffleID, Vec<UserID>>,
+    point_events: Vec<PointEvent>,
+    // The sum of each partecipant's point events, kept up to date so that priorities are not recounted
+    point_totals: HashMap<(RaffleID, UserID), i64>,
+    rng: StdRng,
+    code_format: CodeFormat,
+    clock: Clock,
+}
+
+struct StoredRaffle {
+    raffle: Raffle,
+    seed: DrawSeed,
+}
+
+struct StoredPartecipant {
+    joined_when: Timestamp,
+    left_when: Option<Timestamp>,
+    campaign: Option<String>,
+}
+
+struct StoredUsedCode {
+    raffle_id: RaffleID,
+    user_id: UserID,
+    code_id: RedeemableCodeId,
+    used_when: Timestamp,
+}
+
+impl MemoryInstance {
+    pub fn create() -> MemoryInstance {
+        MemoryInstance::with_rng(StdRng::from_entropy())
+    }
+
+    pub fn create_with_seed(seed: u64) -> MemoryInstance {
+        MemoryInstance::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: StdRng) -> MemoryInstance {
+        MemoryInstance {
+            raffles: BTreeMap::new(),
+            partecipants: BTreeMap::new(),
+            codes: BTreeMap::new(),
+            code_owners: HashMap::new(),
+            used_codes: vec![],
+            referrals: vec![],
+            referral_tokens: BTreeMap::new(),
+            referral_slugs: BTreeMap::new(),
+            user_settings: HashMap::new(),
+            winners: BTreeMap::new(),
+            point_events: vec![],
+            point_totals: HashMap::new(),
+            rng,
+            code_format: CodeFormat::default(),
+            clock: Box::new(timestamp_now)
+        }
+    }
+
+    fn is_ongoing(&self, raffle_id: RaffleID) -> bool {
+        self.raffles.get(&raffle_id)
+            .is_some_and(|stored| stored.raffle.ended_when.is_none())
+    }
+
+    fn ongoing_raffle_mut(&mut self, raffle_id: RaffleID) -> RaffleResult<&mut StoredRaffle> {
+        match self.raffles.get_mut(&raffle_id) {
+            Some(stored) if stored.raffle.ended_when.is_none() => Ok(stored),
+            Some(_) => Err(RaffleDbError::NoOngoingRaffle(raffle_id)),
+            None => Err(RaffleDbError::RaffleNotFound(raffle_id))
+        }
+    }
+
+    fn is_usable(&self, code: &RedeemableCode) -> bool {
+        (code.remaining_uses > 0 || code.remaining_uses == -1) && !code.is_expired((self.clock)()) && self.is_ongoing(code.raffle_id)
+    }
+
+    fn make_partecipant(&self, raffle_id: RaffleID, user_id: UserID, stored: &StoredPartecipant) -> Partecipant {
+        Partecipant {
+            user_id,
+            joined_when: stored.joined_when,
+            priority: self.point_totals.get(&(raffle_id, user_id)).copied().unwrap_or_default().max(0) as usize
+        }
+    }
+
+    fn record_points(&mut self, raffle_id: RaffleID, user_id: UserID, points: i64, source: PointSource) {
+        *self.point_totals.entry((raffle_id, user_id)).or_default() += points;
+        self.point_events.push(PointEvent {
+            raffle_id,
+            user_id,
+            points,
+            source,
+            created_when: (self.clock)()
+        });
+    }
+}
+
+impl Default for MemoryInstance {
+    fn default() -> Self {
+        MemoryInstance::create()
+    }
+}
+
+impl RaffleDB for MemoryInstance {
+    fn close(self) -> RaffleResult<()> {
+        Ok(())
+    }
+
+    fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    // raffle functions
+    fn create_raffle(&mut self, name: &str, description: &str, draw_mode: DrawMode, scoring_rules: ScoringRules) -> RaffleResult<RaffleCreationResult> {
+        validate_scoring_rules(&scoring_rules)?;
+        let same_name_raffle = self.get_ongoing_raffles()?
+            .into_iter()
+            .find(|raffle| raffle.raffle_name == name);
+        if let Some(existing_raffle) = same_name_raffle {
+            return Ok(RaffleCreationResult::OngoingRaffleExists(existing_raffle));
+        }
+        let raffle_id = self.raffles.keys().next_back().map_or(1, |last| last + 1);
+        let seed = generate_seed(&mut self.rng);
+        let raffle = Raffle {
+            raffle_id,
+            raffle_name: name.to_owned(),
+            raffle_description: description.to_owned(),
+            started_when: (self.clock)(),
+            ended_when: None,
+            draw_mode,
+            seed_hash: Some(seed_hash(&seed)),
+            scoring_rules,
+        };
+        self.raffles.insert(raffle_id, StoredRaffle {
+            raffle: raffle.clone(),
+            seed
+        });
+        Ok(RaffleCreationResult::Success(raffle))
+    }
+    fn get_ongoing_raffles(&self) -> RaffleResult<Vec<Raffle>> {
+        Ok(Vec::from_iter(self.get_raffles()?
+            .into_iter()
+            .filter(|raffle| raffle.ended_when.is_none())))
+    }
+    fn get_raffle(&self, raffle_id: RaffleID) -> RaffleResult<Option<Raffle>> {
+        Ok(self.raffles.get(&raffle_id).map(|stored| stored.raffle.clone()))
+    }
+    fn get_raffles(&self) -> RaffleResult<Vec<Raffle>> {
+        let mut raffles = Vec::from_iter(self.raffles.values().map(|stored| stored.raffle.clone()));
+        raffles.sort_by_key(|raffle| (raffle.started_when, raffle.raffle_id));
+        Ok(raffles)
+    }
+    fn stop_raffle(&mut self, raffle_id: RaffleID, num_winners: usize) -> RaffleResult<RaffleOutcome> {
+        self.ongoing_raffle_mut(raffle_id)?;
+        let partecipants = Vec::from_iter(self.get_partecipants(raffle_id)?);
+        let partecipants_hash = partecipants_hash(&partecipants);
+        let now = (self.clock)();
+        let stored = self.ongoing_raffle_mut(raffle_id)?;
+        stored.raffle.ended_when = Some(now);
+        let seed = stored.seed;
+        let winners = draw_from_seed(partecipants, num_winners, stored.raffle.draw_mode, &seed);
+        self.winners.insert(raffle_id, Vec::from_iter(winners.iter().map(|winner| winner.user_id)));
+        Ok(RaffleOutcome {
+            winners,
+            seed,
+            partecipants_hash
+        })
+    }
+    fn get_raffle_winners(&self, raffle_id: RaffleID) -> RaffleResult<Vec<UserID>> {
+        Ok(self.winners.get(&raffle_id).cloned().unwrap_or_default())
+    }
+
+    // user functions
+    fn get_partecipants(&self, raffle_id: RaffleID) -> RaffleResult<HashSet<Partecipant>> {
+        Ok(HashSet::from_iter(self.partecipants
+            .range((raffle_id, UserID::MIN)..=(raffle_id, UserID::MAX))
+            .filter(|(_, stored)| stored.left_when.is_none())
+            .map(|((_, user_id), stored)| self.make_partecipant(raffle_id, *user_id, stored))))
+    }
+    fn get_partecipant(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Option<Partecipant>> {
+        Ok(self.partecipants.get(&(raffle_id, user_id))
+            .filter(|stored| stored.left_when.is_none())
+            .map(|stored| self.make_partecipant(raffle_id, user_id, stored)))
+    }
+    fn is_partecipant(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<bool> {
+        Ok(self.partecipants.get(&(raffle_id, user_id))
+            .is_some_and(|stored| stored.left_when.is_none()))
+    }
+    fn register_partecipant(&mut self, raffle_id: RaffleID, user_id: UserID, referrer: Option<UserID>, campaign: Option<&str>) -> RaffleResult<RegistrationStatus> {
+        let campaign = validate_campaign(campaign)?;
+        let rules = match self.raffles.get(&raffle_id) {
+            Some(stored) if stored.raffle.ended_when.is_none() => stored.raffle.scoring_rules,
+            _ => return Ok(RegistrationStatus::NoRaffleOngoing)
+        };
+        if self.is_partecipant(raffle_id, user_id)? {
+            return Ok(RegistrationStatus::NotRegistered);
+        }
+        let previous_campaign = self.partecipants.get(&(raffle_id, user_id)).map(|stored| stored.campaign.clone());
+        let has_joined_before = previous_campaign.is_some();
+        self.partecipants.insert((raffle_id, user_id), StoredPartecipant {
+            joined_when: (self.clock)(),
+            left_when: None,
+            campaign: previous_campaign.unwrap_or(campaign)
+        });
+        if !has_joined_before {
+            self.record_points(raffle_id, user_id, rules.join_points, PointSource::Join);
+        }
+        let mut referred_by = None;
+        if let Some(referrer_id) = referrer {
+            let already_referred = self.referrals.iter()
+                .any(|(raffle, referral)| *raffle == raffle_id && referral.referee == user_id);
+            if referrer_id != user_id && !already_referred && self.is_partecipant(raffle_id, referrer_id)? {
+                self.referrals.push((raffle_id, Referral {
+                    referrer: referrer_id,
+                    referee: user_id
+                }));
+                self.record_points(raffle_id, referrer_id, rules.referral_points, PointSource::Referral(user_id));
+                referred_by = Some(referrer_id);
+            }
+        }
+        match (self.get_partecipant(raffle_id, user_id)?, referred_by) {
+            (Some(partecipant), Some(referrer_id)) => Ok(RegistrationStatus::Referred(partecipant, referrer_id)),
+            (Some(partecipant), None) => Ok(RegistrationStatus::Registered(partecipant)),
+            (None, _) => Err(RaffleDbError::NotFound(format!("partecipant {} was registered to raffle {} but is missing", user_id, raffle_id)))
+        }
+    }
+    fn remove_partecipant(&mut self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<bool> {
+        match self.partecipants.get_mut(&(raffle_id, user_id)) {
+            Some(stored) if stored.left_when.is_none() => {
+                stored.left_when = Some((self.clock)());
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+    fn get_registration_status(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<RegistrationStatus> {
+        Ok(if let Some(partecipant) = self.get_partecipant(raffle_id, user_id)? {
+            RegistrationStatus::Registered(partecipant)
+        } else {
+            RegistrationStatus::NotRegistered
+        })
+    }
+    fn get_referees_of_user(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Vec<UserID>> {
+        Ok(Vec::from_iter(self.referrals.iter()
+            .filter(|(raffle, referral)| *raffle == raffle_id && referral.referrer == user_id)
+            .map(|(_, referral)| referral.referee)))
+    }
+    fn get_referees(&self, raffle_id: RaffleID, referrer: UserID) -> RaffleResult<Vec<Referee>> {
+        let mut referees = Vec::from_iter(self.get_referees_of_user(raffle_id, referrer)?.into_iter()
+            .filter_map(|referee| self.partecipants.get(&(raffle_id, referee)).map(|stored| Referee {
+                user_id: referee,
+                joined_when: stored.joined_when,
+                left_when: stored.left_when,
+                points: self.point_events.iter()
+                    .filter(|event| event.raffle_id == raffle_id && event.user_id == referrer && event.source == PointSource::Referral(referee))
+                    .map(|event| event.points)
+                    .sum()
+            })));
+        referees.sort_by_key(|referee| (referee.joined_when, referee.user_id));
+        Ok(referees)
+    }
+    fn get_referrer_of_user(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Option<UserID>> {
+        Ok(self.referrals.iter()
+            .find(|(raffle, referral)| *raffle == raffle_id && referral.referee == user_id)
+            .map(|(_, referral)| referral.referrer))
+    }
+    fn get_point_events(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Vec<PointEvent>> {
+        Ok(Vec::from_iter(self.point_events.iter()
+            .filter(|event| event.raffle_id == raffle_id && event.user_id == user_id)
+            .cloned()))
+    }
+    fn get_referral_token(&mut self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Option<ReferralToken>> {
+        if !self.is_partecipant(raffle_id, user_id)? {
+            return Ok(None);
+        }
+        let existing = self.referral_tokens.values()
+            .find(|token| token.raffle_id == raffle_id && token.user_id == user_id);
+        if let Some(existing) = existing {
+            return Ok(Some(existing.clone()));
+        }
+        let token = (0..CODE_GENERATION_ATTEMPTS)
+            .map(|_| generate_referral_token(&mut self.rng))
+            .find(|token| !self.referral_tokens.contains_key(token))
+            .ok_or_else(|| RaffleDbError::ConstraintViolation(format!("no unused referral token found in {} attempts", CODE_GENERATION_ATTEMPTS)))?;
+        let token = ReferralToken {
+            token,
+            raffle_id,
+            user_id,
+            created_when: (self.clock)()
+        };
+        self.referral_tokens.insert(token.token.clone(), token.clone());
+        Ok(Some(token))
+    }
+    fn find_referral_token(&self, token: &str) -> RaffleResult<Option<ReferralToken>> {
+        Ok(self.referral_tokens.get(&normalize_code(token)).cloned())
+    }
+    fn claim_referral_slug(&mut self, user_id: UserID, slug: &str) -> RaffleResult<SlugClaimResult> {
+        let slug = match validate_slug(slug) {
+            Ok(slug) => slug,
+            Err(result) => return Ok(result)
+        };
+        match self.referral_slugs.get(&slug) {
+            Some(owner) if *owner == user_id => return Ok(SlugClaimResult::Claimed(slug)),
+            Some(_) => return Ok(SlugClaimResult::Taken),
+            None => {}
+        }
+        self.referral_slugs.retain(|_, owner| *owner != user_id);
+        self.referral_slugs.insert(slug.clone(), user_id);
+        Ok(SlugClaimResult::Claimed(slug))
+    }
+    fn get_referral_slug(&self, user_id: UserID) -> RaffleResult<Option<String>> {
+        Ok(self.referral_slugs.iter()
+            .find(|(_, owner)| **owner == user_id)
+            .map(|(slug, _)| slug.clone()))
+    }
+    fn find_referral_slug(&self, slug: &str) -> RaffleResult<Option<UserID>> {
+        Ok(self.referral_slugs.get(&slug.trim().to_ascii_lowercase()).copied())
+    }
+    fn get_user_settings(&self, user_id: UserID) -> RaffleResult<UserSettings> {
+        Ok(self.user_settings.get(&user_id).copied().unwrap_or_default())
+    }
+    fn set_user_settings(&mut self, user_id: UserID, settings: UserSettings) -> RaffleResult<()> {
+        self.user_settings.insert(user_id, settings);
+        Ok(())
+    }
+    fn get_campaign_joins(&self, raffle_id: RaffleID) -> RaffleResult<Vec<CampaignJoins>> {
+        let mut campaigns: BTreeMap<Option<String>, CampaignJoins> = BTreeMap::new();
+        for ((_, _), stored) in self.partecipants.range((raffle_id, UserID::MIN)..=(raffle_id, UserID::MAX)) {
+            let joins = campaigns.entry(stored.campaign.clone()).or_insert_with(|| CampaignJoins {
+                campaign: stored.campaign.clone(),
+                joins: 0,
+                partecipants: 0
+            });
+            joins.joins += 1;
+            if stored.left_when.is_none() {
+                joins.partecipants += 1;
+            }
+        }
+        let mut campaigns = Vec::from_iter(campaigns.into_values());
+        campaigns.sort_by(|a, b| b.joins.cmp(&a.joins)
+            .then(a.campaign.is_none().cmp(&b.campaign.is_none()))
+            .then(a.campaign.cmp(&b.campaign)));
+        Ok(campaigns)
+    }
+    fn adjust_points(&mut self, raffle_id: RaffleID, user_id: UserID, points: i64, admin: UserID, reason: &str) -> RaffleResult<Option<Partecipant>> {
+        validate_adjustment(points, reason)?;
+        self.ongoing_raffle_mut(raffle_id)?;
+        if !self.is_partecipant(raffle_id, user_id)? {
+            return Ok(None);
+        }
+        self.record_points(raffle_id, user_id, points, PointSource::Adjustment { admin, reason: reason.trim().to_owned() });
+        self.get_partecipant(raffle_id, user_id)
+    }
+    fn get_referrals(&self, raffle_id: RaffleID) -> RaffleResult<Vec<Referral>> {
+        Ok(Vec::from_iter(self.referrals.iter()
+            .filter(|(raffle, _)| *raffle == raffle_id)
+            .map(|(_, referral)| referral.clone())))
+    }
+
+    // raffle codes functions
+    fn generate_raffle_code(&mut self, raffle_id: RaffleID, use_count: CodeUseCount, options: CodeOptions) -> RaffleResult<RedeemableCode> {
+        self.generate_raffle_codes(raffle_id, 1, use_count, options)?
+            .pop()
+            .ok_or_else(|| RaffleDbError::NotFound("the code was generated but is missing".to_owned()))
+    }
+    fn generate_raffle_codes(&mut self, raffle_id: RaffleID, count: usize, use_count: CodeUseCount, options: CodeOptions) -> RaffleResult<Vec<RedeemableCode>> {
+        self.ongoing_raffle_mut(raffle_id)?;
+        validate_batch_size(count)?;
+        let numeric_usages = numeric_usages(use_count)?;
+        let now = (self.clock)();
+        let options = validate_code_options(options, now)?;
+        let mut taken = HashSet::<String>::from_iter(self.codes.values().map(|code| code.code.clone()));
+        let mut codes = Vec::with_capacity(count);
+        let first_id = self.codes.keys().next_back().map_or(1, |last| last + 1);
+        // Nothing is stored until every code is found, so that a failure leaves no codes behind
+        for unique_id in first_id..first_id + count as RedeemableCodeId {
+            let new_code = (0..CODE_GENERATION_ATTEMPTS)
+                .map(|_| self.code_format.generate(&mut self.rng))
+                .find(|new_code| !taken.contains(new_code))
+                .ok_or_else(|| RaffleDbError::ConstraintViolation(format!("no unused code found in {} attempts", CODE_GENERATION_ATTEMPTS)))?;
+            taken.insert(new_code.clone());
+            codes.push(RedeemableCode {
+                code: new_code,
+                unique_id,
+                raffle_id,
+                remaining_uses: numeric_usages,
+                generated_when: now,
+                points: options.points,
+                label: options.label.clone(),
+                expires_when: options.expires_when,
+                revoked_when: None,
+            });
+        }
+        for code in codes.iter() {
+            self.codes.insert(code.unique_id, code.clone());
+            if !options.owners.is_empty() {
+                self.code_owners.insert(code.unique_id, options.owners.clone());
+            }
+        }
+        Ok(codes)
+    }
+    fn set_code_format(&mut self, format: CodeFormat) -> RaffleResult<()> {
+        self.code_format = format.validated()?;
+        Ok(())
+    }
+    fn get_code_format(&self) -> CodeFormat {
+        self.code_format.clone()
+    }
+    fn get_raffle_codes(&self, raffle_id: RaffleID) -> RaffleResult<HashSet<RedeemableCode>> {
+        Ok(HashSet::from_iter(self.codes.values()
+            .filter(|code| code.raffle_id == raffle_id)
+            .cloned()))
+    }
+    fn get_raffle_codes_used_by_user(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<HashSet<RedeemableCodeId>> {
+        Ok(HashSet::from_iter(self.used_codes.iter()
+            .filter(|used| used.raffle_id == raffle_id && used.user_id == user_id)
+            .map(|used| used.code_id)))
+    }
+    fn get_used_codes(&self, raffle_id: RaffleID) -> RaffleResult<Vec<UsedCode>> {
+        let mut used_codes = Vec::from_iter(self.used_codes.iter()
+            .filter(|used| used.raffle_id == raffle_id)
+            .filter_map(|used| self.codes.get(&used.code_id).map(|code| UsedCode {
+                partecpiant_user_id: used.user_id,
+                code: code.code.clone(),
+                used_when: used.used_when,
+            })));
+        used_codes.sort_by_key(|used| used.used_when);
+        Ok(used_codes)
+    }
+    fn find_raffle_code(&self, name: &str) -> RaffleResult<Option<RedeemableCode>> {
+        let name = normalize_code(name);
+        Ok(self.codes.values()
+            .find(|code| code.code == name)
+            .cloned())
+    }
+    fn get_raffle_code_by_name(&self, name: &str) -> RaffleResult<Option<RedeemableCode>> {
+        let name = normalize_code(name);
+        Ok(self.codes.values()
+            .find(|code| code.code == name && self.is_usable(code))
+            .cloned())
+    }
+    fn get_raffle_code_by_id(&self, code: RedeemableCodeId) -> RaffleResult<Option<RedeemableCode>> {
+        Ok(self.codes.get(&code)
+            .filter(|code| self.is_usable(code))
+            .cloned())
+    }
+    fn get_code_owners(&self, code_id: RedeemableCodeId) -> RaffleResult<Vec<UserID>> {
+        Ok(self.code_owners.get(&code_id).cloned().unwrap_or_default())
+    }
+    fn partecipant_has_redeemed_code(&self, partecipant_id: UserID, code_id: RedeemableCodeId) -> RaffleResult<bool> {
+        Ok(self.used_codes.iter()
+            .any(|used| used.user_id == partecipant_id && used.code_id == code_id))
+    }
+    fn delete_raffle_code(&mut self, code: RedeemableCodeId) -> RaffleResult<()> {
+        let now = (self.clock)();
+        if let Some(code) = self.codes.get_mut(&code) {
+            code.remaining_uses = 0;
+            code.revoked_when.get_or_insert(now);
+        }
+        Ok(())
+    }
+
+    fn validate_code(&self, code: &str) -> RaffleResult<CodeValidation> {
+        Ok(if let Some(redeemable_code) = self.get_raffle_code_by_name(code)? {
+            CodeValidation::Valid(redeemable_code.unique_id)
+        } else if self.find_raffle_code(code)?.is_some_and(|found| found.is_expired((self.clock)())) {
+            CodeValidation::NotValid(EXPIRED_CODE.to_owned())
+        } else {
+            CodeValidation::NotValid("Code not found".to_owned())
+        })
+    }
+    fn redeem_code(&mut self, user_id: UserID, code_id: RedeemableCodeId) -> RaffleResult<CodeRedeemalResult> {
+        let (code, code_points) = match self.codes.get(&code_id).map(|code| (code, self.raffles.get(&code.raffle_id))) {
+            Some((code, Some(stored))) if stored.raffle.ended_when.is_none() =>
+                (code, code.points.unwrap_or(stored.raffle.scoring_rules.code_points)),
+            _ => return Ok(CodeRedeemalResult::NonExistingCode)
+        };
+        let now = (self.clock)();
+        if code.is_expired(now) {
+            return Ok(CodeRedeemalResult::Expired);
+        }
+        if self.code_owners.get(&code_id).is_some_and(|owners| !owners.contains(&user_id)) {
+            return Ok(CodeRedeemalResult::NotAllowed);
+        }
+        let raffle_id = code.raffle_id;
+        let remaining_uses = code.remaining_uses;
+        if !self.is_partecipant(raffle_id, user_id)? {
+            return Ok(CodeRedeemalResult::NonExistingUser);
+        }
+        if self.partecipant_has_redeemed_code(user_id, code_id)? {
+            return Ok(CodeRedeemalResult::AlreadyRedeemed);
+        }
+        if remaining_uses == 0 {
+            return Ok(CodeRedeemalResult::Exhausted);
+        }
+        self.used_codes.push(StoredUsedCode {
+            raffle_id,
+            user_id,
+            code_id,
+            used_when: now
+        });
+        self.record_points(raffle_id, user_id, code_points, PointSource::Code(code_id));
+        if let Some(stored_code) = self.codes.get_mut(&code_id) {
+            if stored_code.remaining_uses > 0 {
+                stored_code.remaining_uses -= 1;
+            }
+        }
+        Ok(CodeRedeemalResult::Redeemed(code_points))
+    }
+}
@@ -0,0 +1,334 @@
+use std::fmt::Display;
+use rusqlite::Connection;
+
+/*
+The schema of the SQLite database is built only through these migrations:
+the version of a database is stored in its user_version pragma, and when it is opened
+every migration with a greater version is applied in order, each in its own transaction.
+To change the schema add a new migration at the end of the list, never edit the ones already released.
+*/
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        // Databases made before migrations existed are at version 0 but already have these tables
+        sql: "
+        CREATE TABLE IF NOT EXISTS PARTECIPANTS (
+            user_id INTEGER NOT NULL PRIMARY KEY,
+            joined_when INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS REDEEMABLE_CODES (
+            code_id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            code TEXT NOT NULL UNIQUE,
+            remaining_uses INTEGER NOT NULL,
+            generated_when INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS USED_CODES (
+            user_id INTEGER NOT NULL,
+            code_id INTEGER NOT NULL,
+            used_when INTEGER NOT NULL
+        );
+        --The referrer is the user that invited the referee in the raffle
+        CREATE TABLE IF NOT EXISTS REFERRALS (
+            referrer_id INTEGER NOT NULL,
+            referee_id INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS RAFFLE (
+            raffle_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            raffle_name TEXT NOT NULL,
+            raffle_message BLOB NOT NULL,
+            started_when INTEGER NOT NULL,
+            ended_when INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS RAFFLE_WINNERS (
+            raffle_id INTEGER,
+            winner_id INTEGER,
+            position INTEGER,
+            FOREIGN KEY (raffle_id) REFERENCES RAFFLE(raffle_id)
+        );
+        "
+    },
+    Migration {
+        version: 2,
+        description: "Draw mode and commit-reveal seeds for raffles",
+        sql: "
+        ALTER TABLE RAFFLE ADD COLUMN draw_mode INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE RAFFLE ADD COLUMN seed BLOB;
+        ALTER TABLE RAFFLE ADD COLUMN seed_hash TEXT;
+        ALTER TABLE RAFFLE ADD COLUMN partecipants_hash TEXT;
+        --Raffles started before draw modes existed keep picking the partecipants with the most points
+        UPDATE RAFFLE SET draw_mode = 1;
+        "
+    },
+    Migration {
+        version: 3,
+        description: "Keep the partecipants, referrals and codes of every raffle",
+        // Before this the tables were wiped when a raffle ended, so any row left belongs to the ongoing raffle
+        sql: "
+        CREATE TEMP TABLE ONGOING_RAFFLE AS
+            SELECT MAX(raffle_id) AS raffle_id FROM RAFFLE WHERE ended_when IS NULL;
+
+        --Partecipants who leave are kept with left_when set, so that the raffle history is never lost
+        CREATE TABLE PARTECIPANTS_V3 (
+            raffle_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            joined_when INTEGER NOT NULL,
+            left_when INTEGER,
+            PRIMARY KEY (raffle_id, user_id),
+            FOREIGN KEY (raffle_id) REFERENCES RAFFLE(raffle_id)
+        );
+        INSERT INTO PARTECIPANTS_V3 (raffle_id, user_id, joined_when)
+            SELECT ONGOING_RAFFLE.raffle_id, user_id, joined_when FROM PARTECIPANTS, ONGOING_RAFFLE
+            WHERE ONGOING_RAFFLE.raffle_id IS NOT NULL;
+        DROP TABLE PARTECIPANTS;
+        ALTER TABLE PARTECIPANTS_V3 RENAME TO PARTECIPANTS;
+
+        CREATE TABLE REDEEMABLE_CODES_V3 (
+            code_id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            raffle_id INTEGER NOT NULL,
+            code TEXT NOT NULL UNIQUE,
+            remaining_uses INTEGER NOT NULL,
+            generated_when INTEGER NOT NULL,
+            FOREIGN KEY (raffle_id) REFERENCES RAFFLE(raffle_id)
+        );
+        INSERT INTO REDEEMABLE_CODES_V3 (code_id, raffle_id, code, remaining_uses, generated_when)
+            SELECT code_id, ONGOING_RAFFLE.raffle_id, code, remaining_uses, generated_when FROM REDEEMABLE_CODES, ONGOING_RAFFLE
+            WHERE ONGOING_RAFFLE.raffle_id IS NOT NULL;
+        DROP TABLE REDEEMABLE_CODES;
+        ALTER TABLE REDEEMABLE_CODES_V3 RENAME TO REDEEMABLE_CODES;
+
+        CREATE TABLE USED_CODES_V3 (
+            raffle_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            code_id INTEGER NOT NULL,
+            used_when INTEGER NOT NULL,
+            FOREIGN KEY (raffle_id) REFERENCES RAFFLE(raffle_id),
+            FOREIGN KEY (code_id) REFERENCES REDEEMABLE_CODES(code_id)
+        );
+        INSERT INTO USED_CODES_V3 (raffle_id, user_id, code_id, used_when)
+            SELECT ONGOING_RAFFLE.raffle_id, user_id, code_id, used_when FROM USED_CODES, ONGOING_RAFFLE
+            WHERE ONGOING_RAFFLE.raffle_id IS NOT NULL;
+        DROP TABLE USED_CODES;
+        ALTER TABLE USED_CODES_V3 RENAME TO USED_CODES;
+
+        --The referrer is the user that invited the referee in the raffle
+        CREATE TABLE REFERRALS_V3 (
+            raffle_id INTEGER NOT NULL,
+            referrer_id INTEGER NOT NULL,
+            referee_id INTEGER NOT NULL,
+            FOREIGN KEY (raffle_id) REFERENCES RAFFLE(raffle_id)
+        );
+        INSERT INTO REFERRALS_V3 (raffle_id, referrer_id, referee_id)
+            SELECT ONGOING_RAFFLE.raffle_id, referrer_id, referee_id FROM REFERRALS, ONGOING_RAFFLE
+            WHERE ONGOING_RAFFLE.raffle_id IS NOT NULL;
+        DROP TABLE REFERRALS;
+        ALTER TABLE REFERRALS_V3 RENAME TO REFERRALS;
+
+        DROP TABLE ONGOING_RAFFLE;
+        "
+    },
+    Migration {
+        version: 4,
+        description: "A partecipant can redeem a code only once",
+        // Concurrent redeems could have stored the same code twice for a user, only the first one counts
+        sql: "
+        DELETE FROM USED_CODES WHERE rowid NOT IN (
+            SELECT MIN(rowid) FROM USED_CODES GROUP BY user_id, code_id
+        );
+        CREATE UNIQUE INDEX USED_CODES_USER_CODE ON USED_CODES (user_id, code_id);
+        "
+    },
+    Migration {
+        version: 5,
+        description: "Indexes to count the points of every partecipant in one query",
+        sql: "
+        CREATE INDEX REFERRALS_REFERRER ON REFERRALS (raffle_id, referrer_id);
+        CREATE INDEX REFERRALS_REFEREE ON REFERRALS (raffle_id, referee_id);
+        CREATE INDEX USED_CODES_PARTECIPANT ON USED_CODES (raffle_id, user_id);
+        "
+    },
+    Migration {
+        version: 6,
+        description: "Ledger of the points given to partecipants",
+        // The points everyone had until now are written in the ledger, one point for each join, referral and code
+        sql: "
+        CREATE TABLE POINT_EVENTS (
+            event_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            raffle_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            points INTEGER NOT NULL,
+            source INTEGER NOT NULL, --0 join, 1 referral, 2 code, 3 adjustment
+            source_ref INTEGER, --The referee, the code or the admin who made the adjustment
+            reason TEXT,
+            created_when INTEGER NOT NULL,
+            FOREIGN KEY (raffle_id) REFERENCES RAFFLE(raffle_id)
+        );
+        CREATE INDEX POINT_EVENTS_PARTECIPANT ON POINT_EVENTS (raffle_id, user_id);
+
+        INSERT INTO POINT_EVENTS (raffle_id, user_id, points, source, created_when)
+            SELECT raffle_id, user_id, 1, 0, joined_when FROM PARTECIPANTS;
+        INSERT INTO POINT_EVENTS (raffle_id, user_id, points, source, source_ref, created_when)
+            SELECT REFERRALS.raffle_id, REFERRALS.referrer_id, 1, 1, REFERRALS.referee_id, COALESCE(PARTECIPANTS.joined_when, 0)
+            FROM REFERRALS LEFT JOIN PARTECIPANTS
+                ON PARTECIPANTS.raffle_id == REFERRALS.raffle_id AND PARTECIPANTS.user_id == REFERRALS.referee_id;
+        INSERT INTO POINT_EVENTS (raffle_id, user_id, points, source, source_ref, created_when)
+            SELECT raffle_id, user_id, 1, 2, code_id, used_when FROM USED_CODES;
+        "
+    },
+    Migration {
+        version: 7,
+        description: "Scoring rules of each raffle",
+        sql: "
+        ALTER TABLE RAFFLE ADD COLUMN join_points INTEGER NOT NULL DEFAULT 1;
+        ALTER TABLE RAFFLE ADD COLUMN referral_points INTEGER NOT NULL DEFAULT 1;
+        ALTER TABLE RAFFLE ADD COLUMN code_points INTEGER NOT NULL DEFAULT 1;
+        "
+    },
+    Migration {
+        version: 8,
+        description: "Point value and label of each code",
+        // A NULL value means the code is worth the code_points of its raffle
+        sql: "
+        ALTER TABLE REDEEMABLE_CODES ADD COLUMN points INTEGER;
+        ALTER TABLE REDEEMABLE_CODES ADD COLUMN label TEXT;
+        "
+    },
+    Migration {
+        version: 9,
+        description: "Expiry of codes",
+        // NULL for the codes that never expire
+        sql: "
+        ALTER TABLE REDEEMABLE_CODES ADD COLUMN expires_when INTEGER;
+        "
+    },
+    Migration {
+        version: 10,
+        description: "Personal codes",
+        // A code with no owners can be redeemed by anyone
+        sql: "
+        CREATE TABLE CODE_OWNERS (
+            code_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            PRIMARY KEY (code_id, user_id),
+            FOREIGN KEY (code_id) REFERENCES REDEEMABLE_CODES(code_id)
+        );
+        "
+    },
+    Migration {
+        version: 11,
+        description: "Join campaigns",
+        // NULL for the partecipants who joined without a campaign link, and for everyone who joined before
+        sql: "
+        ALTER TABLE PARTECIPANTS ADD COLUMN campaign TEXT;
+        "
+    },
+    Migration {
+        version: 12,
+        description: "Referral tokens",
+        // A token stays valid after its partecipant leaves, but only partecipants can refer
+        sql: "
+        CREATE TABLE REFERRAL_TOKENS (
+            token TEXT PRIMARY KEY,
+            raffle_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            created_when INTEGER NOT NULL,
+            UNIQUE (raffle_id, user_id),
+            FOREIGN KEY (raffle_id) REFERENCES RAFFLE(raffle_id)
+        );
+        "
+    },
+    Migration {
+        version: 13,
+        description: "Vanity referral slugs",
+        // One slug per user, kept across raffles
+        sql: "
+        CREATE TABLE REFERRAL_SLUGS (
+            slug TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL UNIQUE,
+            claimed_when INTEGER NOT NULL
+        );
+        "
+    },
+    Migration {
+        version: 14,
+        description: "User settings",
+        // Users who never changed their settings have no row
+        sql: "
+        CREATE TABLE USER_SETTINGS (
+            user_id INTEGER PRIMARY KEY,
+            referral_notifications INTEGER NOT NULL
+        );
+        "
+    },
+    Migration {
+        version: 15,
+        description: "Revoked codes",
+        // Codes revoked before this have no date, they only show as used up
+        sql: "
+        ALTER TABLE REDEEMABLE_CODES ADD COLUMN revoked_when INTEGER;
+        "
+    },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    ReadVersion(rusqlite::Error),
+    // The database was written by a newer release of the bot
+    DatabaseTooNew { database_version: u32, latest_version: u32 },
+    Failed { version: u32, description: &'static str, cause: rusqlite::Error },
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::ReadVersion(cause) =>
+                write!(f, "Could not read the schema version of the database: {}", cause),
+            MigrationError::DatabaseTooNew { database_version, latest_version } =>
+                write!(f, "The database is at schema version {}, but this build only knows up to version {}", database_version, latest_version),
+            MigrationError::Failed { version, description, cause } =>
+                write!(f, "Migration {} ({}) failed and was rolled back: {}", version, description, cause),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+pub fn schema_version(connection: &Connection) -> rusqlite::Result<u32> {
+    connection.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+pub fn run_migrations(connection: &mut Connection) -> Result<(), MigrationError> {
+    let database_version = schema_version(connection).map_err(MigrationError::ReadVersion)?;
+    if database_version > latest_version() {
+        return Err(MigrationError::DatabaseTooNew {
+            database_version,
+            latest_version: latest_version()
+        });
+    }
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > database_version) {
+        apply_migration(connection, migration)
+            .map_err(|cause| MigrationError::Failed {
+                version: migration.version,
+                description: migration.description,
+                cause
+            })?;
+    }
+    Ok(())
+}
+
+fn apply_migration(connection: &mut Connection, migration: &Migration) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(migration.sql)?;
+    transaction.execute_batch(&format!("PRAGMA user_version = {}", migration.version))?;
+    transaction.commit()
+}
@@ -1 +1,122 @@
-pub mod sqlite_instance;
\ No newline at end of file
+pub mod sqlite_instance;
+pub mod memory_instance;
+pub mod migrations;
+
+use crate::db::{CodeOptions, CodeUseCount, RaffleDbError, RaffleResult, ScoringRules, SlugClaimResult, Timestamp};
+
+pub fn timestamp_now() -> Timestamp {
+    // A clock set before 1970 gives 0 rather than a panic
+    std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// How many times a code can be used as stored in the db, -1 means illimited
+pub(crate) fn numeric_usages(use_count: CodeUseCount) -> RaffleResult<i32> {
+    match use_count {
+        CodeUseCount::Counted(n) if n > 0 => Ok(n),
+        CodeUseCount::Once => Ok(1),
+        CodeUseCount::Illimited => Ok(-1),
+        invalid => Err(RaffleDbError::InvalidUseCount(invalid))
+    }
+}
+
+// New codes are random, so one may clash with an existing code: a few more tries make that vanishingly rare
+pub(crate) const CODE_GENERATION_ATTEMPTS: usize = 10;
+
+pub const MAX_CODES_PER_BATCH: usize = 10_000;
+
+pub(crate) fn validate_batch_size(count: usize) -> RaffleResult<()> {
+    if count == 0 || count > MAX_CODES_PER_BATCH {
+        Err(RaffleDbError::ConstraintViolation(format!("between 1 and {} codes can be generated at once, not {}", MAX_CODES_PER_BATCH, count)))
+    } else {
+        Ok(())
+    }
+}
+
+// Adjustments must change something and say why
+pub(crate) fn validate_adjustment(points: i64, reason: &str) -> RaffleResult<()> {
+    if points == 0 {
+        Err(RaffleDbError::ConstraintViolation("an adjustment must give or take at least one point".to_owned()))
+    } else if reason.trim().is_empty() {
+        Err(RaffleDbError::ConstraintViolation("an adjustment must have a reason".to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+// Points can be zero, to give nothing for something, but never negative
+pub(crate) fn validate_scoring_rules(rules: &ScoringRules) -> RaffleResult<()> {
+    if rules.join_points < 0 || rules.referral_points < 0 || rules.code_points < 0 {
+        Err(RaffleDbError::ConstraintViolation(format!("scoring rules can't take points away: {:?}", rules)))
+    } else {
+        Ok(())
+    }
+}
+
+// A code can't take points away nor be expired already, and a blank label is no label
+pub(crate) fn validate_code_options(options: CodeOptions, now: Timestamp) -> RaffleResult<CodeOptions> {
+    if let Some(points) = options.points.filter(|points| *points < 0) {
+        return Err(RaffleDbError::ConstraintViolation(format!("a code can't be worth {} points", points)));
+    }
+    if let Some(expires_when) = options.expires_when.filter(|expires_when| *expires_when <= now) {
+        return Err(RaffleDbError::ConstraintViolation(format!("a code can't expire at {}, that is not in the future", expires_when)));
+    }
+    let mut owners = options.owners;
+    owners.sort_unstable();
+    owners.dedup();
+    Ok(CodeOptions {
+        points: options.points,
+        label: options.label
+            .map(|label| label.trim().to_owned())
+            .filter(|label| !label.is_empty()),
+        expires_when: options.expires_when,
+        owners
+    })
+}
+
+pub const MAX_CAMPAIGN_LENGTH: usize = 32;
+
+// Campaign tags are stored lowercase, made of letters, digits and _ so that they fit in start links
+pub fn normalize_campaign(campaign: &str) -> Option<String> {
+    let campaign = campaign.to_ascii_lowercase();
+    let valid = !campaign.is_empty() && campaign.len() <= MAX_CAMPAIGN_LENGTH
+        && campaign.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid { Some(campaign) } else { None }
+}
+
+pub(crate) fn validate_campaign(campaign: Option<&str>) -> RaffleResult<Option<String>> {
+    match campaign {
+        Some(campaign) => match normalize_campaign(campaign) {
+            Some(campaign) => Ok(Some(campaign)),
+            None => Err(RaffleDbError::ConstraintViolation(format!("{:?} is not a valid campaign, use up to {} letters, digits and _", campaign, MAX_CAMPAIGN_LENGTH)))
+        },
+        None => Ok(None)
+    }
+}
+
+pub const MIN_SLUG_LENGTH: usize = 3;
+pub const MAX_SLUG_LENGTH: usize = 24;
+// Slugs someone could use to pass as the bot or its staff
+pub const RESERVED_SLUGS: &[&str] = &[
+    "admin", "admins", "administrator", "bot", "code", "codes", "help", "manager", "mod", "moderator",
+    "official", "raffle", "raffles", "staff", "start", "support", "team", "winner", "winners",
+];
+
+// Slugs are stored lowercase, made of letters, digits and _ and starting with a letter, None if it can't be one
+pub fn normalize_slug(slug: &str) -> Option<String> {
+    let slug = slug.trim().to_ascii_lowercase();
+    let valid = slug.len() >= MIN_SLUG_LENGTH && slug.len() <= MAX_SLUG_LENGTH
+        && slug.starts_with(|c: char| c.is_ascii_alphabetic())
+        && slug.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid { Some(slug) } else { None }
+}
+
+// The slug to store, or why it can't be claimed
+pub(crate) fn validate_slug(slug: &str) -> Result<String, SlugClaimResult> {
+    let slug = normalize_slug(slug).ok_or(SlugClaimResult::Invalid)?;
+    if RESERVED_SLUGS.contains(&slug.as_str()) {
+        return Err(SlugClaimResult::Reserved);
+    }
+    Ok(slug)
+}
+
+pub(crate) const EXPIRED_CODE: &str = "The code has expired";
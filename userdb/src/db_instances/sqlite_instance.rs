@@ -1,95 +1,175 @@
 use std::collections::HashSet;
-use std::error::Error;
-use std::ops::Add;
-use rand::Rng;
-use rusqlite::{Connection, Result, params};
-use crate::db::RaffleResult;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rusqlite::{Connection, ErrorCode, OptionalExtension, Result, TransactionBehavior, params};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, Type, ValueRef};
 use crate::db::*;
+use crate::db_instances::{CODE_GENERATION_ATTEMPTS, EXPIRED_CODE, numeric_usages, timestamp_now, validate_adjustment, validate_batch_size, validate_campaign, validate_slug, validate_code_options, validate_scoring_rules};
+use crate::codes::{CodeFormat, generate_referral_token, normalize_code};
+use crate::db_instances::migrations::run_migrations;
+use crate::draw::{DrawSeed, SEED_LENGTH, draw_from_seed, generate_seed, partecipants_hash, seed_hash};
 
 pub struct SQLiteInstance {    
     connection: rusqlite::Connection,
+    rng: StdRng,
+    code_format: CodeFormat,
+    clock: Clock,
 }
 
-fn timestamp_now() -> Timestamp {
-    std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs()
+impl ToSql for DrawMode {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(match self {
+            DrawMode::Weighted => 0,
+            DrawMode::Leaderboard => 1,
+        }))
+    }
+}
+impl FromSql for DrawMode {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(DrawMode::Weighted),
+            1 => Ok(DrawMode::Leaderboard),
+            n => Err(FromSqlError::OutOfRange(n))
+        }
+    }
 }
 
-fn setup_connection(connection: &mut Connection) {
-    connection.execute_batch("
-    CREATE TABLE IF NOT EXISTS PARTECIPANTS (
-        user_id INTEGER NOT NULL PRIMARY KEY,
-        joined_when INTEGER NOT NULL
-    );
-    CREATE TABLE IF NOT EXISTS REDEEMABLE_CODES (
-        code_id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-        code TEXT NOT NULL UNIQUE,
-        remaining_uses INTEGER NOT NULL,
-        generated_when INTEGER NOT NULL
-    );
-    CREATE TABLE IF NOT EXISTS USED_CODES (
-        user_id INTEGER NOT NULL,
-        code_id INTEGER NOT NULL,
-        used_when INTEGER NOT NULL
-    );
-    --The referrer is the user that invited the referee in the raffle
-    CREATE TABLE IF NOT EXISTS REFERRALS (
-        referrer_id INTEGER NOT NULL,
-        referee_id INTEGER NOT NULL
-    );
-    CREATE TABLE IF NOT EXISTS RAFFLE (
-        raffle_id INTEGER PRIMARY KEY AUTOINCREMENT,
-        raffle_name TEXT NOT NULL,
-        raffle_message BLOB NOT NULL,
-        started_when INTEGER NOT NULL,
-        ended_when INTEGER
-    );
-    CREATE TABLE IF NOT EXISTS RAFFLE_WINNERS (
-        raffle_id INTEGER,
-        winner_id INTEGER,
-        position INTEGER,
-        FOREIGN KEY (raffle_id) REFERENCES RAFFLE(raffle_id)
-    );
-    ").expect("Failed to create or intialize the database")
+fn raffle_from_row(row: &rusqlite::Row) -> Result<Raffle> {
+    Ok(Raffle {
+        raffle_id: row.get("raffle_id")?,
+        raffle_name : row.get("raffle_name")?,
+        raffle_description : row.get("raffle_message")?,
+        started_when : row.get("started_when")?,
+        ended_when : row.get("ended_when")?,
+        draw_mode : row.get("draw_mode")?,
+        seed_hash : row.get("seed_hash")?,
+        scoring_rules: ScoringRules {
+            join_points: row.get("join_points")?,
+            referral_points: row.get("referral_points")?,
+            code_points: row.get("code_points")?,
+        },
+    })
 }
-fn raffle_from_row(row: &rusqlite::Row) -> Raffle {
-    Raffle {
-        raffle_id: row.get_unwrap(0),
-        raffle_name : row.get_unwrap(1),
-        raffle_description : row.get_unwrap(2),
-        started_when : row.get_unwrap(3)
-    }
+fn raffle_code_from_row(row: &rusqlite::Row) -> Result<RedeemableCode> {
+    Ok(RedeemableCode {
+        unique_id: row.get("code_id")?,
+        raffle_id: row.get("raffle_id")?,
+        code : row.get("code")?,
+        remaining_uses : row.get("remaining_uses")?,
+        generated_when: row.get("generated_when")?,
+        points: row.get("points")?,
+        label: row.get("label")?,
+        expires_when: row.get("expires_when")?,
+        revoked_when: row.get("revoked_when")?,
+    })
 }
-fn raffle_code_from_row(row: &rusqlite::Row) -> RedeemableCode {
-    RedeemableCode {
-        unique_id: row.get_unwrap(0),
-        code : row.get_unwrap(1),
-        remaining_uses : row.get_unwrap(2),
-        generated_when: row.get_unwrap(3),
+
+/*
+Selects every partecipant with its priority, the sum of its point events in the same raffle,
+counted by the database in one query. Append the WHERE clause on PARTECIPANTS to it.
+*/
+const PARTECIPANTS_WITH_PRIORITY: &str = "
+    SELECT PARTECIPANTS.user_id, PARTECIPANTS.joined_when,
+        MAX(0, COALESCE((SELECT SUM(points) FROM POINT_EVENTS
+            WHERE POINT_EVENTS.raffle_id == PARTECIPANTS.raffle_id AND POINT_EVENTS.user_id == PARTECIPANTS.user_id), 0))
+        AS priority
+    FROM PARTECIPANTS";
+
+fn partecipant_from_row(row: &rusqlite::Row) -> Result<Partecipant> {
+    Ok(Partecipant {
+        user_id: row.get("user_id")?,
+        joined_when: row.get("joined_when")?,
+        priority: row.get::<_, i64>("priority")? as usize,
+    })
+}
+
+fn referral_token_from_row(row: &rusqlite::Row) -> Result<ReferralToken> {
+    Ok(ReferralToken {
+        token: row.get("token")?,
+        raffle_id: row.get("raffle_id")?,
+        user_id: row.get("user_id")?,
+        created_when: row.get("created_when")?,
+    })
+}
+
+// How a PointSource is stored: the kind of source, the id it refers to and the reason
+fn point_source_columns(source: &PointSource) -> (i64, Option<i64>, Option<&str>) {
+    match source {
+        PointSource::Join => (0, None, None),
+        PointSource::Referral(referee) => (1, Some(*referee), None),
+        PointSource::Code(code_id) => (2, Some(*code_id as i64), None),
+        PointSource::Adjustment { admin, reason } => (3, Some(*admin), Some(reason.as_str())),
     }
 }
 
-fn partecipant_from_row(row: &rusqlite::Row, db: &SQLiteInstance) -> Partecipant {
-    let user_id = row.get_unwrap(0);
-    let referees = db.get_referees_of_user(user_id).unwrap().len();
-    let codes_used = db.get_raffle_codes_used_by_user(user_id).unwrap().len();
-    Partecipant {
-        user_id,
-        joined_when: row.get_unwrap(1),
-        priority: 1 + referees + codes_used
+fn point_event_from_row(row: &rusqlite::Row) -> Result<PointEvent> {
+    let source_ref: Option<i64> = row.get("source_ref")?;
+    let source = match (row.get::<_, i64>("source")?, source_ref) {
+        (0, _) => PointSource::Join,
+        (1, Some(referee)) => PointSource::Referral(referee),
+        (2, Some(code_id)) => PointSource::Code(code_id as RedeemableCodeId),
+        (3, Some(admin)) => PointSource::Adjustment {
+            admin,
+            reason: row.get::<_, Option<String>>("reason")?.unwrap_or_default()
+        },
+        (n, _) => return Err(rusqlite::Error::FromSqlConversionFailure(0, Type::Integer,
+            format!("Unknown point source {}", n).into()))
+    };
+    Ok(PointEvent {
+        raffle_id: row.get("raffle_id")?,
+        user_id: row.get("user_id")?,
+        points: row.get("points")?,
+        source,
+        created_when: row.get("created_when")?,
+    })
+}
+
+fn insert_point_event(connection: &Connection, raffle_id: RaffleID, user_id: UserID, points: i64, source: &PointSource, when: Timestamp) -> Result<()> {
+    let (source, source_ref, reason) = point_source_columns(source);
+    connection.prepare_cached(
+        "INSERT INTO POINT_EVENTS (raffle_id, user_id, points, source, source_ref, reason, created_when)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?
+        .execute(params!(raffle_id, user_id, points, source, source_ref, reason, when))?;
+    Ok(())
+}
+
+impl From<rusqlite::Error> for RaffleDbError {
+    fn from(err: rusqlite::Error) -> Self {
+        match err {
+            rusqlite::Error::SqliteFailure(rusqlite::ffi::Error { code: ErrorCode::ConstraintViolation, .. }, message) =>
+                RaffleDbError::ConstraintViolation(message.unwrap_or_else(|| "constraint failed".to_owned())),
+            err => RaffleDbError::Storage(Box::new(err))
+        }
     }
 }
 
 impl SQLiteInstance {
     
-    pub fn create(file: &str) -> Result<SQLiteInstance, ()> {
-        let  conn = Connection::open(file);
-        if let Ok(mut connection) = conn {
-            setup_connection(&mut connection);
-            Ok(SQLiteInstance {
-                connection
-            })
-        } else {
-            Err(())
+    // Opens the database, upgrading its schema to the latest version if needed
+    pub fn create(file: &str) -> RaffleResult<SQLiteInstance> {
+        SQLiteInstance::open(file, StdRng::from_entropy())
+    }
+
+    pub fn create_with_seed(file: &str, seed: u64) -> RaffleResult<SQLiteInstance> {
+        SQLiteInstance::open(file, StdRng::seed_from_u64(seed))
+    }
+
+    fn open(file: &str, rng: StdRng) -> RaffleResult<SQLiteInstance> {
+        let mut connection = Connection::open(file)?;
+        run_migrations(&mut connection)?;
+        Ok(SQLiteInstance {
+            connection,
+            rng,
+            code_format: CodeFormat::default(),
+            clock: Box::new(timestamp_now)
+        })
+    }
+
+    fn get_ongoing_raffle(&self, raffle_id: RaffleID) -> RaffleResult<Raffle> {
+        match self.get_raffle(raffle_id)? {
+            Some(raffle) if raffle.ended_when.is_none() => Ok(raffle),
+            Some(_) => Err(RaffleDbError::NoOngoingRaffle(raffle_id)),
+            None => Err(RaffleDbError::RaffleNotFound(raffle_id))
         }
     }
 }
@@ -98,342 +178,586 @@ impl RaffleDB for SQLiteInstance {
     fn close(self) -> RaffleResult<()> {
         match self.connection.close() {
             Ok(_) => Ok(()),
-            Err((_c, e)) => Err(Box::new(e))
+            Err((_c, e)) => Err(e.into())
         }
     }
+
+    fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
     
     // raffle functions
-    fn create_raffle(&mut self, name: &str, description: &str) -> RaffleResult<RaffleCreationResult> {
-        let ongoing_raffle = self.get_ongoing_raffle()?;
-        if let Some(existing_raffle) = ongoing_raffle {
+    fn create_raffle(&mut self, name: &str, description: &str, draw_mode: DrawMode, scoring_rules: ScoringRules) -> RaffleResult<RaffleCreationResult> {
+        validate_scoring_rules(&scoring_rules)?;
+        let same_name_raffle = self.get_ongoing_raffles()?
+            .into_iter()
+            .find(|raffle| raffle.raffle_name == name);
+        if let Some(existing_raffle) = same_name_raffle {
             Ok(RaffleCreationResult::OngoingRaffleExists(existing_raffle))
         } else {
-            let time_since_epoch = timestamp_now();
-            let insertion = self.connection.execute("
-            INSERT INTO RAFFLE (raffle_name, raffle_message, started_when)
-            VALUES (?1, ?2, ?3)
-            ", params!(name, description, time_since_epoch));
-            if let Err(e) = insertion {
-                Err(Box::new(e))
-            } else {
-                Ok(RaffleCreationResult::Success(self.get_ongoing_raffle()?
-                                            .expect("Raffle was created but was not correctly inserted in db")))
-            }
+            let time_since_epoch = (self.clock)();
+            let seed = generate_seed(&mut self.rng);
+            self.connection.execute("
+            INSERT INTO RAFFLE (raffle_name, raffle_message, started_when, draw_mode, seed, seed_hash, join_points, referral_points, code_points)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            ", params!(name, description, time_since_epoch, draw_mode, &seed[..], seed_hash(&seed),
+                scoring_rules.join_points, scoring_rules.referral_points, scoring_rules.code_points))?;
+            let raffle_id = self.connection.last_insert_rowid() as RaffleID;
+            let raffle = self.get_raffle(raffle_id)?
+                .ok_or(RaffleDbError::RaffleNotFound(raffle_id))?;
+            Ok(RaffleCreationResult::Success(raffle))
         }
     }
-    fn get_ongoing_raffle(&self) -> RaffleResult<Option<Raffle>> {
-        let running_raffle= self.connection.query_row("
-        SELECT * FROM RAFFLE WHERE RAFFLE.ended_when IS NULL
-        ", params!(), |row| Ok(raffle_from_row(&row)));
-        
-        match running_raffle {
-            Ok(raffle) => Ok(Some(raffle)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(Box::new(e))
-        }
+    fn get_ongoing_raffles(&self) -> RaffleResult<Vec<Raffle>> {
+        let mut raffles_query = self.connection.prepare_cached(
+            "SELECT * FROM RAFFLE
+            WHERE ended_when IS NULL
+            ORDER BY started_when, raffle_id")?;
+        let raffles = raffles_query.query_map([], raffle_from_row)?;
+        Ok(raffles.collect::<Result<Vec<_>>>()?)
+    }
+    fn get_raffle(&self, raffle_id: RaffleID) -> RaffleResult<Option<Raffle>> {
+        Ok(self.connection.query_row("
+        SELECT * FROM RAFFLE WHERE raffle_id == ?1
+        ", params!(raffle_id), raffle_from_row)
+            .optional()?)
     }
-    fn stop_raffle(&mut self, num_winners: usize) -> RaffleResult<Vec<Partecipant>> {
-        let ongoing_raffle = self.get_ongoing_raffle()?;
-        if let Some(raffle) = ongoing_raffle {
-            let partecipants_set = self.get_partecipants()?;
-            let transaction = self.connection.transaction()
-                .expect("stop_raffle: failed to begin SQL transaction");
-            let _ = transaction.execute_batch("
-                DELETE FROM REFERRALS;
-                DELETE FROM USED_CODES;
-                DELETE FROM REDEEMABLE_CODES;
-                DELETE FROM PARTECIPANTS;
-            ")
-            .unwrap();
+    fn get_raffles(&self) -> RaffleResult<Vec<Raffle>> {
+        let mut raffles_query = self.connection.prepare_cached(
+            "SELECT * FROM RAFFLE ORDER BY started_when, raffle_id")?;
+        let raffles = raffles_query.query_map([], raffle_from_row)?;
+        Ok(raffles.collect::<Result<Vec<_>>>()?)
+    }
+    fn stop_raffle(&mut self, raffle_id: RaffleID, num_winners: usize) -> RaffleResult<RaffleOutcome> {
+        let raffle = self.get_ongoing_raffle(raffle_id)?;
+        let partecipants_set = self.get_partecipants(raffle_id)?;
+        let stored_seed: Option<Vec<u8>> = self.connection.query_row(
+            "SELECT seed FROM RAFFLE WHERE raffle_id == ?1",
+            params!(raffle.raffle_id),
+            |row| row.get(0))?;
+        let seed: DrawSeed = match stored_seed {
+            Some(bytes) if bytes.len() == SEED_LENGTH => {
+                let mut seed = [0u8; SEED_LENGTH];
+                seed.copy_from_slice(&bytes);
+                seed
+            },
+            // Raffles started before seeds were committed still get a random draw
+            _ => generate_seed(&mut self.rng)
+        };
+        let transaction = self.connection.transaction()?;
 
-            let closed_raffles = {
-                let mut statement = transaction.prepare_cached("
-                UPDATE RAFFLE
-                SET ended_when = ?1
-                WHERE
-                    raffle_id == ?2
-                ").unwrap();
-                statement.execute(params!(timestamp_now(), raffle.raffle_id)).unwrap()
-            };
-            if closed_raffles > 0 {
-                let mut partecipants = Vec::from_iter(partecipants_set.into_iter());
-                partecipants
-                    .sort_by(|a, b|  b.priority.cmp(&a.priority));
-                let winners = Vec::from_iter(partecipants
-                    .iter()
-                    .take(num_winners)
-                    .map(|p| p.clone())
-                );
-                for (pos, winner) in winners.iter().enumerate() {
-                    let mut winner_statement = transaction.
-                        prepare_cached("
-                            INSERT INTO RAFFLE_WINNERS(raffle_id, winner_id, position)
-                            VALUES (?1, ?2, ?3)")
-                            .unwrap();
-                    winner_statement
-                    .execute(params!(raffle.raffle_id, winner.user_id, pos))?;
-                }
-                transaction.commit().unwrap();
-                Ok(winners)
-            } else {
-                transaction.rollback().unwrap();
-                let err: Box::<dyn Error + Send + Sync> = "No raffles were closed?".to_string().into();
-                Err(err)
+        let partecipants = Vec::from_iter(partecipants_set);
+        let partecipants_hash = partecipants_hash(&partecipants);
+        let closed_raffles = {
+            let mut statement = transaction.prepare_cached("
+            UPDATE RAFFLE
+            SET ended_when = ?1, seed = ?2, partecipants_hash = ?3
+            WHERE
+                raffle_id == ?4 AND ended_when IS NULL
+            ")?;
+            statement.execute(params!((self.clock)(), &seed[..], partecipants_hash, raffle.raffle_id))?
+        };
+        if closed_raffles == 0 {
+            return Err(RaffleDbError::NoOngoingRaffle(raffle_id));
+        }
+        let winners = draw_from_seed(partecipants, num_winners, raffle.draw_mode, &seed);
+        {
+            let mut winner_statement = transaction.prepare_cached("
+                INSERT INTO RAFFLE_WINNERS(raffle_id, winner_id, position)
+                VALUES (?1, ?2, ?3)")?;
+            for (pos, winner) in winners.iter().enumerate() {
+                winner_statement.execute(params!(raffle.raffle_id, winner.user_id, pos))?;
             }
-        } else {
-            let err: Box::<dyn Error + Send + Sync> = "No running raffles".to_string().into();
-            Err(err)
         }
+        transaction.commit()?;
+        Ok(RaffleOutcome {
+            winners,
+            seed,
+            partecipants_hash
+        })
+    }
+    fn get_raffle_winners(&self, raffle_id: RaffleID) -> RaffleResult<Vec<UserID>> {
+        let mut winners_query = self.connection.prepare_cached(
+            "SELECT winner_id FROM RAFFLE_WINNERS
+            WHERE raffle_id == ?1
+            ORDER BY position")?;
+        let winners = winners_query.query_map(params!(raffle_id), |row| row.get(0))?;
+        Ok(winners.collect::<Result<Vec<_>>>()?)
     }
 
     // user functions
-    fn get_partecipants(&self) -> RaffleResult<HashSet<Partecipant>> {
-        let mut partecipants_statement = self.connection.prepare_cached(
-            "SELECT * FROM PARTECIPANTS"
-        ).unwrap();
-        let partecipants_from_db = partecipants_statement.
-            query_map([], 
-                |row| Ok(partecipant_from_row(&row, &self))
-            )?;
-        Ok(HashSet::from_iter(partecipants_from_db.map(|row| row.unwrap())))
-    }
-    fn is_partecipant(&self, user_id: UserID) -> RaffleResult<bool> {
+    fn get_partecipants(&self, raffle_id: RaffleID) -> RaffleResult<HashSet<Partecipant>> {
+        let mut partecipants_statement = self.connection.prepare_cached(&format!(
+            "{} WHERE PARTECIPANTS.raffle_id == ?1 AND PARTECIPANTS.left_when IS NULL", PARTECIPANTS_WITH_PRIORITY))?;
+        let partecipants = partecipants_statement.query_map(params!(raffle_id), partecipant_from_row)?;
+        Ok(partecipants.collect::<Result<HashSet<_>>>()?)
+    }
+    fn is_partecipant(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<bool> {
         let mut partecipant_query = self.connection.prepare_cached(
             "SELECT COUNT(*) FROM PARTECIPANTS
             WHERE
-                user_id == ?1").unwrap();
-        let partecipant_count = partecipant_query
-            .query_row(
-                params!(user_id), 
-                |row| Ok(row.get_unwrap::<usize, u64>(0))
-            )?;
+                raffle_id == ?1 AND user_id == ?2 AND left_when IS NULL")?;
+        let partecipant_count: u64 = partecipant_query
+            .query_row(params!(raffle_id, user_id), |row| row.get(0))?;
         Ok(partecipant_count > 0)
     }
-    fn get_partecipant(&self, user_id: UserID) -> RaffleResult<Option<Partecipant>> {
-        let mut partecipant_query = self.connection.prepare_cached(
-            "SELECT * FROM PARTECIPANTS
-            WHERE
-                user_id == ?1").unwrap();
-        let partecipant_maybe = partecipant_query.query_row(params!(user_id), |row| Ok(partecipant_from_row(&row, &self)));
-        Ok(if let Ok(partecipant) = partecipant_maybe {
-            Some(partecipant)
-        } else {
-            None
-        })
+    fn get_partecipant(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Option<Partecipant>> {
+        let mut partecipant_query = self.connection.prepare_cached(&format!(
+            "{} WHERE PARTECIPANTS.raffle_id == ?1 AND PARTECIPANTS.user_id == ?2 AND PARTECIPANTS.left_when IS NULL", PARTECIPANTS_WITH_PRIORITY))?;
+        Ok(partecipant_query.query_row(params!(raffle_id, user_id), partecipant_from_row)
+            .optional()?)
     }
-    fn register_partecipant(&mut self, user_id: UserID, referrer: Option<UserID>) -> RaffleResult<RegistrationStatus>{
-        let raffle = self.get_ongoing_raffle().unwrap();
-        if raffle.is_none() {
-            return Ok(RegistrationStatus::NoRaffleOngoing);
-        }
-        let mut register_query = self.connection.prepare_cached(
-            "INSERT INTO PARTECIPANTS (user_id, joined_when) 
-            SELECT ?1, ?2 WHERE ?1 NOT IN
-            (SELECT user_id from PARTECIPANTS)").unwrap();
-        let now = timestamp_now();
-        let inserted_rows = register_query.execute(params!(user_id, now))?;
+    fn register_partecipant(&mut self, raffle_id: RaffleID, user_id: UserID, referrer: Option<UserID>, campaign: Option<&str>) -> RaffleResult<RegistrationStatus>{
+        let campaign = validate_campaign(campaign)?;
+        let rules = match self.get_raffle(raffle_id)? {
+            Some(raffle) if raffle.ended_when.is_none() => raffle.scoring_rules,
+            _ => return Ok(RegistrationStatus::NoRaffleOngoing)
+        };
+        let now = (self.clock)();
+        let transaction = self.connection.transaction()?;
+        // A partecipant who left is registered again by clearing left_when
+        let inserted_rows = transaction.prepare_cached(
+            "INSERT INTO PARTECIPANTS (raffle_id, user_id, joined_when, campaign) 
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (raffle_id, user_id) DO UPDATE
+            SET joined_when = excluded.joined_when, left_when = NULL
+            WHERE left_when IS NOT NULL")?
+            .execute(params!(raffle_id, user_id, now, campaign))?;
         if inserted_rows == 0 {
-            Ok(RegistrationStatus::NotRegistered)
-        } else {
-            // We did insert the partecipant in the raffle, now let's check if it has a referrer
-            if let Some(referrer_id) = referrer {
-                if self.is_partecipant(referrer_id)? {
-                    let mut referral_query = self.connection.prepare_cached(
-                        "INSERT INTO REFERRALS (referrer_id, referee_id)
-                        SELECT ?1, ?2 WHERE 
-                        ?1 != ?2 -- avoid self-referral
-                        AND (?1, ?2) NOT IN (SELECT referrer_id, referee_id from REFERRALS) -- avoid people leaving and then re-referring the same user
-                        AND (?1) IN (SELECT user_id FROM PARTECIPANTS) AND (?2) IN (SELECT user_id FROM PARTECIPANTS) -- TO OPTIMIZE ensure referee and referrer are valid partecipants"
-                    ).unwrap();
-                    referral_query.execute(params!(referrer_id, user_id))?;
-                }
+            return Ok(RegistrationStatus::NotRegistered);
+        }
+        let has_joined_before: bool = transaction.query_row(
+            "SELECT EXISTS (SELECT 1 FROM POINT_EVENTS WHERE raffle_id == ?1 AND user_id == ?2 AND source == 0)",
+            params!(raffle_id, user_id),
+            |row| row.get(0))?;
+        if !has_joined_before {
+            insert_point_event(&transaction, raffle_id, user_id, rules.join_points, &PointSource::Join, now)?;
+        }
+        // We did insert the partecipant in the raffle, now let's check if it has a referrer
+        let mut referred_by = None;
+        if let Some(referrer_id) = referrer {
+            let referrals = transaction.prepare_cached(
+                "INSERT INTO REFERRALS (raffle_id, referrer_id, referee_id)
+                SELECT ?3, ?1, ?2 WHERE 
+                ?1 != ?2 -- avoid self-referral
+                AND EXISTS (SELECT 1 FROM PARTECIPANTS WHERE raffle_id == ?3 AND user_id == ?1 AND left_when IS NULL) -- only partecipants can refer
+                AND NOT EXISTS (SELECT 1 FROM REFERRALS WHERE raffle_id == ?3 AND referee_id == ?2) -- avoid people leaving and then being referred again"
+            )?
+                .execute(params!(referrer_id, user_id, raffle_id))?;
+            if referrals > 0 {
+                insert_point_event(&transaction, raffle_id, referrer_id, rules.referral_points, &PointSource::Referral(user_id), now)?;
+                referred_by = Some(referrer_id);
             }
-            Ok(RegistrationStatus::Registered(self.get_partecipant(user_id)?.unwrap()))
         }
-        
+        transaction.commit()?;
+        match (self.get_partecipant(raffle_id, user_id)?, referred_by) {
+            (Some(partecipant), Some(referrer_id)) => Ok(RegistrationStatus::Referred(partecipant, referrer_id)),
+            (Some(partecipant), None) => Ok(RegistrationStatus::Registered(partecipant)),
+            (None, _) => Err(RaffleDbError::NotFound(format!("partecipant {} was registered to raffle {} but is missing", user_id, raffle_id)))
+        }
     }
-    fn remove_partecipant(&mut self, user_id: UserID) -> RaffleResult<bool> {
+    fn remove_partecipant(&mut self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<bool> {
         let mut remove_query = self.connection.prepare_cached(
-            "DELETE FROM PARTECIPANTS
-            WHERE user_id == ?1").unwrap();
-        let result = remove_query.execute(params!(user_id))?;
+            "UPDATE PARTECIPANTS
+            SET left_when = ?3
+            WHERE raffle_id == ?1 AND user_id == ?2 AND left_when IS NULL")?;
+        let result = remove_query.execute(params!(raffle_id, user_id, (self.clock)()))?;
         Ok(result > 0)
     }
-    fn get_registration_status(&self, user_id: UserID) -> RaffleResult<RegistrationStatus> {
-        Ok(if let Some(partecipant) = self.get_partecipant(user_id)? {
+    fn get_registration_status(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<RegistrationStatus> {
+        Ok(if let Some(partecipant) = self.get_partecipant(raffle_id, user_id)? {
             RegistrationStatus::Registered(partecipant)
         } else {
             RegistrationStatus::NotRegistered
         })
     }
-    fn get_referees_of_user(&self, user_id: UserID) -> RaffleResult<Vec<UserID>> {
+    fn get_referees_of_user(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Vec<UserID>> {
         let mut referees_query = self.connection.prepare_cached(
             "SELECT referee_id FROM REFERRALS
-            WHERE referrer_id == ?1").unwrap();
-        let resulting_rows = referees_query.query_map(params!(user_id), 
+            WHERE raffle_id == ?1 AND referrer_id == ?2")?;
+        let resulting_rows = referees_query.query_map(params!(raffle_id, user_id), 
         |row| row.get(0))?;
-        Ok(Vec::from_iter(resulting_rows.into_iter().map(|row| row.unwrap())))
+        Ok(resulting_rows.collect::<Result<Vec<_>>>()?)
+    }
+    fn get_referees(&self, raffle_id: RaffleID, referrer: UserID) -> RaffleResult<Vec<Referee>> {
+        let mut referees_query = self.connection.prepare_cached(
+            "SELECT REFERRALS.referee_id, PARTECIPANTS.joined_when, PARTECIPANTS.left_when,
+                COALESCE((SELECT SUM(points) FROM POINT_EVENTS
+                    WHERE POINT_EVENTS.raffle_id == REFERRALS.raffle_id AND POINT_EVENTS.user_id == REFERRALS.referrer_id
+                    AND source == 1 AND source_ref == REFERRALS.referee_id), 0)
+            FROM REFERRALS JOIN PARTECIPANTS
+                ON PARTECIPANTS.raffle_id == REFERRALS.raffle_id AND PARTECIPANTS.user_id == REFERRALS.referee_id
+            WHERE REFERRALS.raffle_id == ?1 AND REFERRALS.referrer_id == ?2
+            ORDER BY PARTECIPANTS.joined_when, REFERRALS.referee_id")?;
+        let referees = referees_query.query_map(params!(raffle_id, referrer),
+        |row| Ok(Referee {
+            user_id: row.get(0)?,
+            joined_when: row.get(1)?,
+            left_when: row.get(2)?,
+            points: row.get(3)?,
+        }))?;
+        Ok(referees.collect::<Result<Vec<_>>>()?)
     }
-    fn get_referrer_of_user(&self, user_id: UserID) -> RaffleResult<Option<UserID>> {
+    fn get_referrer_of_user(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Option<UserID>> {
         let mut referees_query = self.connection.prepare_cached(
             "SELECT referrer_id FROM REFERRALS
-            WHERE referee_id == ?1").unwrap();
-        let resulting_rows = referees_query.query_row(params!(user_id), 
-        |row| Ok(row.get_unwrap(0)));
-        match resulting_rows {
-            Ok(referrer_id) => Ok(Some(referrer_id)),
-            Err(_) => Ok(None),
+            WHERE raffle_id == ?1 AND referee_id == ?2")?;
+        Ok(referees_query.query_row(params!(raffle_id, user_id), |row| row.get(0))
+            .optional()?)
+    }
+    fn get_point_events(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Vec<PointEvent>> {
+        let mut events_query = self.connection.prepare_cached(
+            "SELECT * FROM POINT_EVENTS
+            WHERE raffle_id == ?1 AND user_id == ?2
+            ORDER BY created_when, event_id")?;
+        let events = events_query.query_map(params!(raffle_id, user_id), point_event_from_row)?;
+        Ok(events.collect::<Result<Vec<_>>>()?)
+    }
+    fn get_referral_token(&mut self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<Option<ReferralToken>> {
+        if !self.is_partecipant(raffle_id, user_id)? {
+            return Ok(None);
+        }
+        let existing = self.connection.prepare_cached(
+            "SELECT * FROM REFERRAL_TOKENS WHERE raffle_id == ?1 AND user_id == ?2")?
+            .query_row(params!(raffle_id, user_id), referral_token_from_row)
+            .optional()?;
+        if existing.is_some() {
+            return Ok(existing);
+        }
+        let now = (self.clock)();
+        let mut query = self.connection.prepare_cached(
+            "INSERT INTO REFERRAL_TOKENS (token, raffle_id, user_id, created_when)
+            VALUES (?1, ?2, ?3, ?4)")?;
+        let mut attempt = 1;
+        loop {
+            let token = generate_referral_token(&mut self.rng);
+            // The partecipant has no token yet, so only the uniqueness of the token can fail
+            match query.execute(params!(token, raffle_id, user_id, now)) {
+                Ok(_) => return Ok(Some(ReferralToken {
+                    token,
+                    raffle_id,
+                    user_id,
+                    created_when: now
+                })),
+                Err(e) => match RaffleDbError::from(e) {
+                    RaffleDbError::ConstraintViolation(_) if attempt < CODE_GENERATION_ATTEMPTS => attempt += 1,
+                    e => return Err(e)
+                }
+            }
         }
     }
-    // raffle codes functions
-    fn generate_raffle_code(&mut self, use_count: CodeUseCount) -> RaffleResult<RedeemableCode>{
-        let numeric_usages = match use_count {
-            CodeUseCount::Counted(n) => n,
-            CodeUseCount::Once => 1,
-            CodeUseCount::Illimited => -1,
-            _ => panic!("Passed an invalid use count")
+    fn find_referral_token(&self, token: &str) -> RaffleResult<Option<ReferralToken>> {
+        let mut token_query = self.connection.prepare_cached(
+            "SELECT * FROM REFERRAL_TOKENS WHERE token == ?1")?;
+        Ok(token_query.query_row(params!(normalize_code(token)), referral_token_from_row)
+            .optional()?)
+    }
+    fn claim_referral_slug(&mut self, user_id: UserID, slug: &str) -> RaffleResult<SlugClaimResult> {
+        let slug = match validate_slug(slug) {
+            Ok(slug) => slug,
+            Err(result) => return Ok(result)
         };
-        let mut generator = rand::thread_rng();
-        const CODE_LENGTH : usize = 8;
-        let new_code = std::iter::repeat(0)
-            .take(CODE_LENGTH)
-            .fold(String::from(""), 
-                |s, _| 
-                    s.add(generator.gen_range('0'..'Z')
-                    .to_string()
-                    .as_str()
-            )
-        );
-        let mut query = self.connection
-            .prepare_cached(
-                "INSERT INTO REDEEMABLE_CODES (code, remaining_uses, generated_when)
-                VALUES (?1, ?2, ?3)").unwrap();
-        query.execute(params!(new_code, numeric_usages, timestamp_now()))?;
-        Ok(self.get_raffle_code_by_name(new_code.as_str())?.unwrap())
+        let transaction = self.connection.transaction()?;
+        let owner: Option<UserID> = transaction.query_row(
+            "SELECT user_id FROM REFERRAL_SLUGS WHERE slug == ?1",
+            params!(slug),
+            |row| row.get(0))
+            .optional()?;
+        match owner {
+            Some(owner) if owner == user_id => return Ok(SlugClaimResult::Claimed(slug)),
+            Some(_) => return Ok(SlugClaimResult::Taken),
+            None => {}
+        }
+        transaction.execute("DELETE FROM REFERRAL_SLUGS WHERE user_id == ?1", params!(user_id))?;
+        transaction.execute(
+            "INSERT INTO REFERRAL_SLUGS (slug, user_id, claimed_when) VALUES (?1, ?2, ?3)",
+            params!(slug, user_id, (self.clock)()))?;
+        transaction.commit()?;
+        Ok(SlugClaimResult::Claimed(slug))
+    }
+    fn get_referral_slug(&self, user_id: UserID) -> RaffleResult<Option<String>> {
+        let mut slug_query = self.connection.prepare_cached(
+            "SELECT slug FROM REFERRAL_SLUGS WHERE user_id == ?1")?;
+        Ok(slug_query.query_row(params!(user_id), |row| row.get(0))
+            .optional()?)
+    }
+    fn find_referral_slug(&self, slug: &str) -> RaffleResult<Option<UserID>> {
+        let mut slug_query = self.connection.prepare_cached(
+            "SELECT user_id FROM REFERRAL_SLUGS WHERE slug == ?1")?;
+        Ok(slug_query.query_row(params!(slug.trim().to_ascii_lowercase()), |row| row.get(0))
+            .optional()?)
+    }
+    fn get_user_settings(&self, user_id: UserID) -> RaffleResult<UserSettings> {
+        let mut settings_query = self.connection.prepare_cached(
+            "SELECT referral_notifications FROM USER_SETTINGS WHERE user_id == ?1")?;
+        let settings = settings_query.query_row(params!(user_id),
+        |row| Ok(UserSettings {
+            referral_notifications: row.get(0)?
+        }))
+            .optional()?;
+        Ok(settings.unwrap_or_default())
+    }
+    fn set_user_settings(&mut self, user_id: UserID, settings: UserSettings) -> RaffleResult<()> {
+        self.connection.prepare_cached(
+            "INSERT INTO USER_SETTINGS (user_id, referral_notifications)
+            VALUES (?1, ?2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET referral_notifications = excluded.referral_notifications")?
+            .execute(params!(user_id, settings.referral_notifications))?;
+        Ok(())
+    }
+    fn get_campaign_joins(&self, raffle_id: RaffleID) -> RaffleResult<Vec<CampaignJoins>> {
+        let mut campaigns_query = self.connection.prepare_cached(
+            "SELECT campaign, COUNT(*), SUM(left_when IS NULL) FROM PARTECIPANTS
+            WHERE raffle_id == ?1
+            GROUP BY campaign
+            ORDER BY COUNT(*) DESC, campaign IS NULL, campaign")?;
+        let campaigns = campaigns_query.query_map(params!(raffle_id),
+        |row| Ok(CampaignJoins {
+            campaign: row.get(0)?,
+            joins: row.get::<_, i64>(1)? as usize,
+            partecipants: row.get::<_, i64>(2)? as usize,
+        }))?;
+        Ok(campaigns.collect::<Result<Vec<_>>>()?)
+    }
+    fn adjust_points(&mut self, raffle_id: RaffleID, user_id: UserID, points: i64, admin: UserID, reason: &str) -> RaffleResult<Option<Partecipant>> {
+        validate_adjustment(points, reason)?;
+        self.get_ongoing_raffle(raffle_id)?;
+        if !self.is_partecipant(raffle_id, user_id)? {
+            return Ok(None);
+        }
+        let source = PointSource::Adjustment { admin, reason: reason.trim().to_owned() };
+        insert_point_event(&self.connection, raffle_id, user_id, points, &source, (self.clock)())?;
+        self.get_partecipant(raffle_id, user_id)
+    }
+    fn get_referrals(&self, raffle_id: RaffleID) -> RaffleResult<Vec<Referral>> {
+        let mut referrals_query = self.connection.prepare_cached(
+            "SELECT referrer_id, referee_id FROM REFERRALS
+            WHERE raffle_id == ?1")?;
+        let resulting_rows = referrals_query.query_map(params!(raffle_id),
+        |row| Ok(Referral {
+            referrer: row.get(0)?,
+            referee: row.get(1)?,
+        }))?;
+        Ok(resulting_rows.collect::<Result<Vec<_>>>()?)
+    }
+    // raffle codes functions
+    fn generate_raffle_code(&mut self, raffle_id: RaffleID, use_count: CodeUseCount, options: CodeOptions) -> RaffleResult<RedeemableCode>{
+        self.generate_raffle_codes(raffle_id, 1, use_count, options)?
+            .pop()
+            .ok_or_else(|| RaffleDbError::NotFound("the code was generated but is missing".to_owned()))
+    }
+    fn generate_raffle_codes(&mut self, raffle_id: RaffleID, count: usize, use_count: CodeUseCount, options: CodeOptions) -> RaffleResult<Vec<RedeemableCode>> {
+        self.get_ongoing_raffle(raffle_id)?;
+        validate_batch_size(count)?;
+        let numeric_usages = numeric_usages(use_count)?;
+        let now = (self.clock)();
+        let options = validate_code_options(options, now)?;
+        let transaction = self.connection.transaction()?;
+        let mut codes = Vec::with_capacity(count);
+        {
+            let mut query = transaction
+                .prepare_cached(
+                    "INSERT INTO REDEEMABLE_CODES (raffle_id, code, remaining_uses, generated_when, points, label, expires_when)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?;
+            let mut owner_query = transaction
+                .prepare_cached("INSERT INTO CODE_OWNERS (code_id, user_id) VALUES (?1, ?2)")?;
+            for _ in 0..count {
+                let mut attempt = 1;
+                let new_code = loop {
+                    let new_code = self.code_format.generate(&mut self.rng);
+                    // The only constraint that can fail is the uniqueness of the code, and it only fails this insertion
+                    match query.execute(params!(raffle_id, new_code, numeric_usages, now, options.points, options.label, options.expires_when)) {
+                        Ok(_) => break new_code,
+                        Err(e) => match RaffleDbError::from(e) {
+                            RaffleDbError::ConstraintViolation(_) if attempt < CODE_GENERATION_ATTEMPTS => attempt += 1,
+                            e => return Err(e)
+                        }
+                    }
+                };
+                let code_id = transaction.last_insert_rowid() as RedeemableCodeId;
+                for owner in options.owners.iter() {
+                    owner_query.execute(params!(code_id, owner))?;
+                }
+                codes.push(RedeemableCode {
+                    code: new_code,
+                    unique_id: code_id,
+                    raffle_id,
+                    remaining_uses: numeric_usages,
+                    generated_when: now,
+                    points: options.points,
+                    label: options.label.clone(),
+                    expires_when: options.expires_when,
+                    revoked_when: None,
+                });
+            }
+        }
+        transaction.commit()?;
+        Ok(codes)
+    }
+    fn set_code_format(&mut self, format: CodeFormat) -> RaffleResult<()> {
+        self.code_format = format.validated()?;
+        Ok(())
+    }
+    fn get_code_format(&self) -> CodeFormat {
+        self.code_format.clone()
     }
     fn delete_raffle_code(&mut self, code: RedeemableCodeId) -> RaffleResult<()> {
         let mut query = self.connection
         .prepare_cached(
             "UPDATE REDEEMABLE_CODES
-            SET remaining_uses = 0
-            WHERE code_id == ?1").unwrap();
-        let _ = query.execute(params!(code))?;
+            SET remaining_uses = 0, revoked_when = COALESCE(revoked_when, ?2)
+            WHERE code_id == ?1")?;
+        query.execute(params!(code, (self.clock)()))?;
         Ok(())
     }
 
     fn validate_code(&self, code: &str) -> RaffleResult<CodeValidation> {
         Ok(if let Some(redeemable_code) = self.get_raffle_code_by_name(code)? {
             CodeValidation::Valid(redeemable_code.unique_id)
+        } else if self.find_raffle_code(code)?.is_some_and(|found| found.is_expired((self.clock)())) {
+            CodeValidation::NotValid(EXPIRED_CODE.to_owned())
         } else {
             CodeValidation::NotValid("Code not found".to_owned())
         })
     }
     fn redeem_code(&mut self, user_id: UserID, code_id: RedeemableCodeId) -> RaffleResult<CodeRedeemalResult> {
-        let user = self.get_partecipant(user_id)?;
-        if user == None {
+        // IMMEDIATE takes the write lock now, so every check below sees what the other connections committed
+        let redeem_transaction = self.connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let now = (self.clock)();
+        let code_raffle: Option<(RaffleID, i64, Option<Timestamp>)> = redeem_transaction.query_row(
+            "SELECT REDEEMABLE_CODES.raffle_id, COALESCE(REDEEMABLE_CODES.points, RAFFLE.code_points), REDEEMABLE_CODES.expires_when
+            FROM REDEEMABLE_CODES JOIN RAFFLE ON REDEEMABLE_CODES.raffle_id == RAFFLE.raffle_id
+            WHERE code_id == ?1 AND RAFFLE.ended_when IS NULL",
+            params!(code_id),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .optional()?;
+        let (raffle_id, code_points) = match code_raffle {
+            Some((_, _, Some(expires_when))) if now >= expires_when => return Ok(CodeRedeemalResult::Expired),
+            Some((raffle_id, code_points, _)) => (raffle_id, code_points),
+            None => return Ok(CodeRedeemalResult::NonExistingCode)
+        };
+        let (has_owners, is_owner): (bool, bool) = redeem_transaction.query_row(
+            "SELECT
+                EXISTS (SELECT 1 FROM CODE_OWNERS WHERE code_id == ?1),
+                EXISTS (SELECT 1 FROM CODE_OWNERS WHERE code_id == ?1 AND user_id == ?2)",
+            params!(code_id, user_id),
+            |row| Ok((row.get(0)?, row.get(1)?)))?;
+        if has_owners && !is_owner {
+            return Ok(CodeRedeemalResult::NotAllowed);
+        }
+        let partecipant_count: u64 = redeem_transaction.query_row(
+            "SELECT COUNT(*) FROM PARTECIPANTS
+            WHERE raffle_id == ?1 AND user_id == ?2 AND left_when IS NULL",
+            params!(raffle_id, user_id),
+            |row| row.get(0))?;
+        if partecipant_count == 0 {
             return Ok(CodeRedeemalResult::NonExistingUser);
         }
-
-        let code = self.get_raffle_code_by_id(code_id)?;
-        if let Some(_existing_code) = code {
-            if self.partecipant_has_redeemed_code(user_id, code_id)? {
-                Ok(CodeRedeemalResult::AlreadyRedeemed)
-            } else {
-                let redeem_transaction = self.connection
-                    .transaction()?;
-                {
-                    let mut insert_query =
-                    redeem_transaction.prepare_cached("INSERT INTO USED_CODES (user_id, code_id, used_when)
-                        VALUES (?1, ?2, ?3)").unwrap();
-                    insert_query
-                    .execute(
-                        params!(user_id, code_id, timestamp_now())
-                    ).unwrap();
-                    
-                }
-                {
-                    let mut update_codes_query = redeem_transaction.prepare_cached("
-                        UPDATE REDEEMABLE_CODES
-                        SET remaining_uses = remaining_uses - 1
-                        WHERE
-                            code_id == ?1
-                            AND
-                            remaining_uses > 0")
-                        .unwrap();
-                    update_codes_query.execute(params!(code_id)).unwrap();
-                }
-                redeem_transaction.commit().unwrap();
-                Ok(CodeRedeemalResult::Redeemed)
-            }
-        } else {
-            Ok(CodeRedeemalResult::NonExistingCode)
+        // The unique index on (user_id, code_id) rejects a second redeem
+        let inserted = redeem_transaction.execute(
+            "INSERT INTO USED_CODES (raffle_id, user_id, code_id, used_when)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (user_id, code_id) DO NOTHING",
+            params!(raffle_id, user_id, code_id, now))?;
+        if inserted == 0 {
+            return Ok(CodeRedeemalResult::AlreadyRedeemed);
+        }
+        let updated = redeem_transaction.execute(
+            "UPDATE REDEEMABLE_CODES
+            SET remaining_uses = CASE WHEN remaining_uses == -1 THEN -1 ELSE remaining_uses - 1 END
+            WHERE
+                code_id == ?1
+                AND
+                (remaining_uses > 0 OR remaining_uses == -1)",
+            params!(code_id))?;
+        if updated == 0 {
+            return Ok(CodeRedeemalResult::Exhausted);
         }
+        insert_point_event(&redeem_transaction, raffle_id, user_id, code_points, &PointSource::Code(code_id), now)?;
+        redeem_transaction.commit()?;
+        Ok(CodeRedeemalResult::Redeemed(code_points))
     }
 
-    fn get_raffle_codes(&self) -> RaffleResult<HashSet<RedeemableCode>> {
+    fn get_raffle_codes(&self, raffle_id: RaffleID) -> RaffleResult<HashSet<RedeemableCode>> {
         let mut raffle_code_query = self.connection.prepare_cached(
-        "SELECT * FROM REDEEMABLE_CODES").unwrap();
+        "SELECT * FROM REDEEMABLE_CODES
+            WHERE
+                raffle_id == ?1")?;
         let found_codes = raffle_code_query.query_map(
-        params!(),
-        |row| Ok(raffle_code_from_row(row)))?;
-        Ok(HashSet::from_iter(
-            found_codes
-            .into_iter()
-            .map(
-                |row| row.unwrap()
-            )))
+        params!(raffle_id),
+        raffle_code_from_row)?;
+        Ok(found_codes.collect::<Result<HashSet<_>>>()?)
     }
 
-    fn get_raffle_codes_used_by_user(&self, user_id: UserID) -> RaffleResult<HashSet<RedeemableCodeId>> {
+    fn get_raffle_codes_used_by_user(&self, raffle_id: RaffleID, user_id: UserID) -> RaffleResult<HashSet<RedeemableCodeId>> {
         let mut raffle_code_query = self.connection.prepare_cached(
         "SELECT code_id FROM USED_CODES
             WHERE
-                user_id = ?1").unwrap();
+                raffle_id == ?1 AND user_id == ?2")?;
         let found_codes = raffle_code_query.query_map(
-        params!(user_id),
-        |row| Ok(row.get_unwrap(0)))?;
-        Ok(HashSet::from_iter(
-            found_codes
-            .into_iter()
-            .map(|
-                row| row.unwrap()
-            )))
+        params!(raffle_id, user_id),
+        |row| row.get(0))?;
+        Ok(found_codes.collect::<Result<HashSet<_>>>()?)
+    }
+    fn get_used_codes(&self, raffle_id: RaffleID) -> RaffleResult<Vec<UsedCode>> {
+        let mut used_codes_query = self.connection.prepare_cached(
+        "SELECT USED_CODES.user_id, REDEEMABLE_CODES.code, USED_CODES.used_when
+            FROM USED_CODES JOIN REDEEMABLE_CODES ON USED_CODES.code_id == REDEEMABLE_CODES.code_id
+            WHERE
+                USED_CODES.raffle_id == ?1
+            ORDER BY USED_CODES.used_when")?;
+        let used_codes = used_codes_query.query_map(
+        params!(raffle_id),
+        |row| Ok(UsedCode {
+            partecpiant_user_id: row.get(0)?,
+            code: row.get(1)?,
+            used_when: row.get(2)?,
+        }))?;
+        Ok(used_codes.collect::<Result<Vec<_>>>()?)
     }
     fn get_raffle_code_by_id(&self, code: RedeemableCodeId) -> RaffleResult<Option<RedeemableCode>> {
         let mut raffle_code_query = self.connection.prepare_cached(
             "SELECT * FROM REDEEMABLE_CODES
                 WHERE code_id == ?1 
-                AND (remaining_uses > 0 OR remaining_uses == -1)").unwrap();
-        let found_codes = raffle_code_query.query_row(
-            params!(code),
-            |row| Ok(raffle_code_from_row(row)));
-        match found_codes {
-            Ok(code) => Ok(Some(code)),
-            Err(_) => Ok(None),
-        }
+                AND (remaining_uses > 0 OR remaining_uses == -1)
+                AND (expires_when IS NULL OR expires_when > ?2)
+                AND raffle_id IN (SELECT raffle_id FROM RAFFLE WHERE ended_when IS NULL)")?;
+        Ok(raffle_code_query.query_row(params!(code, (self.clock)()), raffle_code_from_row)
+            .optional()?)
+    }
+    fn find_raffle_code(&self, name: &str) -> RaffleResult<Option<RedeemableCode>> {
+        let mut raffle_code_query = self.connection.prepare_cached(
+            "SELECT * FROM REDEEMABLE_CODES WHERE code == ?1")?;
+        Ok(raffle_code_query.query_row(params!(normalize_code(name)), raffle_code_from_row)
+            .optional()?)
     }
     fn get_raffle_code_by_name(&self, name: &str) -> RaffleResult<Option<RedeemableCode>> {
         let mut raffle_code_query = self.connection.prepare_cached(
             "SELECT * FROM REDEEMABLE_CODES
                 WHERE code == ?1 
-                AND (remaining_uses > 0 OR remaining_uses == -1)").unwrap();
-        let found_codes = raffle_code_query.query_row(
-            params!(name),
-            |row| Ok(raffle_code_from_row(row)));
-        match found_codes {
-            Ok(code) => Ok(Some(code)),
-            Err(_) => Ok(None)
-        }
+                AND (remaining_uses > 0 OR remaining_uses == -1)
+                AND (expires_when IS NULL OR expires_when > ?2)
+                AND raffle_id IN (SELECT raffle_id FROM RAFFLE WHERE ended_when IS NULL)")?;
+        Ok(raffle_code_query.query_row(params!(normalize_code(name), (self.clock)()), raffle_code_from_row)
+            .optional()?)
     }
 
+    fn get_code_owners(&self, code_id: RedeemableCodeId) -> RaffleResult<Vec<UserID>> {
+        let mut owners_query = self.connection.prepare_cached(
+            "SELECT user_id FROM CODE_OWNERS WHERE code_id == ?1 ORDER BY user_id")?;
+        let owners = owners_query.query_map(params!(code_id), |row| row.get(0))?;
+        Ok(owners.collect::<Result<Vec<_>>>()?)
+    }
     fn partecipant_has_redeemed_code(&self, partecipant_id: UserID, code_id: RedeemableCodeId) -> RaffleResult<bool> {
         let mut redeem_query = self.connection.prepare_cached(
             "SELECT COUNT(*) FROM USED_CODES
             WHERE
-                user_id == ?1 AND code_id == ?2").unwrap();
-        let result : u8 = redeem_query.query_row(
+                user_id == ?1 AND code_id == ?2")?;
+        let result: u64 = redeem_query.query_row(
             params!(partecipant_id, code_id),
-            |row| Ok(row.get(0).unwrap()))?;
+            |row| row.get(0))?;
         Ok(result > 0)
     }
-}
\ No newline at end of file
+}
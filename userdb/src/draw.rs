@@ -0,0 +1,99 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+use crate::db::{DrawMode, Partecipant};
+
+pub const SEED_LENGTH: usize = 32;
+pub type DrawSeed = [u8; SEED_LENGTH];
+
+/*
+A raffle is drawn with a commit-reveal scheme:
+when the raffle starts a secret seed is generated and only its SHA-256 hash is published,
+when the raffle ends the seed is revealed along with the hash of the partecipants list.
+Anyone with the list can then check the seed against the published hash and run draw_from_seed
+to get the very same winners.
+*/
+pub fn generate_seed<R: Rng + ?Sized>(rng: &mut R) -> DrawSeed {
+    let mut seed = [0u8; SEED_LENGTH];
+    rng.fill(&mut seed);
+    seed
+}
+
+pub fn seed_hash(seed: &DrawSeed) -> String {
+    to_hex(&Sha256::digest(seed))
+}
+
+// Hashes one "user_id:priority" line for each partecipant, sorted by user id
+pub fn partecipants_hash(partecipants: &[Partecipant]) -> String {
+    let mut sorted = Vec::from_iter(partecipants.iter());
+    sorted.sort_by_key(|p| p.user_id);
+    let mut hasher = Sha256::new();
+    for partecipant in sorted {
+        hasher.update(format!("{}:{}\n", partecipant.user_id, partecipant.priority).as_bytes());
+    }
+    to_hex(&hasher.finalize())
+}
+
+pub fn draw_from_seed(partecipants: Vec<Partecipant>, num_winners: usize, mode: DrawMode, seed: &DrawSeed) -> Vec<Partecipant> {
+    draw_winners(partecipants, num_winners, mode, &mut ChaCha20Rng::from_seed(*seed))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn seed_from_hex(hex: &str) -> Option<DrawSeed> {
+    if hex.len() != SEED_LENGTH * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut seed = [0u8; SEED_LENGTH];
+    for (i, byte) in seed.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(seed)
+}
+
+// Picks up to num_winners partecipants, the first element of the returned vec is the first place
+pub fn draw_winners<R: Rng + ?Sized>(partecipants: Vec<Partecipant>, num_winners: usize, mode: DrawMode, rng: &mut R) -> Vec<Partecipant> {
+    match mode {
+        DrawMode::Weighted => weighted_draw(partecipants, num_winners, rng),
+        DrawMode::Leaderboard => leaderboard_draw(partecipants, num_winners),
+    }
+}
+
+fn leaderboard_draw(mut partecipants: Vec<Partecipant>, num_winners: usize) -> Vec<Partecipant> {
+    // On a tie, whoever joined first places higher
+    partecipants.sort_by(|a, b| b.priority.cmp(&a.priority)
+        .then(a.joined_when.cmp(&b.joined_when))
+        .then(a.user_id.cmp(&b.user_id)));
+    partecipants.truncate(num_winners);
+    partecipants
+}
+
+fn weighted_draw<R: Rng + ?Sized>(mut partecipants: Vec<Partecipant>, num_winners: usize, rng: &mut R) -> Vec<Partecipant> {
+    // Sort the tickets so that the same rng always gives the same winners, whatever order the partecipants came in
+    partecipants.sort_by_key(|p| p.user_id);
+    let mut winners = Vec::with_capacity(num_winners.min(partecipants.len()));
+    while winners.len() < num_winners {
+        let total_tickets: usize = partecipants.iter().map(|p| p.priority).sum();
+        if total_tickets == 0 {
+            break;
+        }
+        let mut ticket = rng.gen_range(0..total_tickets);
+        let winner_index = partecipants.iter()
+            .position(|p| {
+                if ticket < p.priority {
+                    true
+                } else {
+                    ticket -= p.priority;
+                    false
+                }
+            });
+        // The ticket is below the total, so some partecipant always holds it
+        match winner_index {
+            Some(winner_index) => winners.push(partecipants.remove(winner_index)),
+            None => break
+        }
+    }
+    winners
+}
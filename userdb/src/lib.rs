@@ -1,8 +1,14 @@
 extern crate rusqlite;
 extern crate rand;
+extern crate rand_chacha;
+extern crate sha2;
 
 pub mod db_instances;
 pub mod db;
+pub mod draw;
+pub mod codes;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
 
 #[cfg(test)]
 mod tests;
\ No newline at end of file
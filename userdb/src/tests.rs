@@ -1,64 +1,305 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use crate::db_instances::sqlite_instance::SQLiteInstance;
+use crate::db_instances::memory_instance::MemoryInstance;
+use crate::conformance::{self, start_raffle};
 use crate::db::*;
+use crate::db_instances::migrations::*;
+use crate::draw::*;
+use crate::codes::*;
 
 #[test]
-fn test_db_raffle_execution() {
-    let mut db = SQLiteInstance::create("./test.db").unwrap();
-    let new_raffle = db.create_raffle("Test Raffle 2", "Test Description").unwrap();
-    assert!(db.register_partecipant(0, None).unwrap() == RegistrationStatus::NoRaffleOngoing);
-    assert!(new_raffle.is_success());
-    db.register_partecipant(0, None).unwrap();
-    assert_eq!(db.get_referrer_of_user(0).unwrap(), None);
-    db.register_partecipant(1, None).unwrap();
-    assert_eq!(db.get_referrer_of_user(1).unwrap(), None);
-    db.register_partecipant(2, Some(1)).unwrap();
-    assert_eq!(db.get_referees_of_user(1).unwrap().len(), 1);
-    assert_eq!(db.get_referrer_of_user(2).unwrap().unwrap(), 1);
-
-    let current_partecipants = db.get_partecipants().unwrap();
-    assert_eq!(current_partecipants.len(), 3);
-
-    assert!(db.remove_partecipant(0).unwrap());
-    let current_partecipants = db.get_partecipants().unwrap();
-    assert_eq!(current_partecipants.len(), 2);
-    assert!(!current_partecipants.iter().fold(false, |v, p| v || p.user_id == 0));
-    assert_eq!(db.get_registration_status(0).unwrap(), RegistrationStatus::NotRegistered);
-    
-
-    let new_code = db.generate_raffle_code(CodeUseCount::Once).unwrap();
-    assert!(match db.validate_code(new_code.code.as_ref()).unwrap() {
-        CodeValidation::Valid(_) => true,
-        _ => false
-    });
-    db.redeem_code(1, new_code.unique_id).unwrap();
-    let validation_now = db.validate_code(new_code.code.as_ref()).unwrap();
-    assert!(match validation_now {
-        CodeValidation::Valid(_) => false,
-        _ => true
-    });
-
-    let new_code = db.generate_raffle_code(CodeUseCount::Counted(10)).unwrap();
-    assert_eq!(db.redeem_code(1, new_code.unique_id).unwrap(), CodeRedeemalResult::Redeemed);
-    assert_eq!(db.redeem_code(1, new_code.unique_id).unwrap(), CodeRedeemalResult::AlreadyRedeemed);
-    assert_eq!(db.redeem_code(2, new_code.unique_id).unwrap(), CodeRedeemalResult::Redeemed);
-    
-    let usage_count = db.get_raffle_code_by_id(new_code.unique_id).unwrap().unwrap().remaining_uses;
-    assert_eq!(usage_count, 8);
-    db.delete_raffle_code(new_code.unique_id).unwrap();
-    let usage_count = db.get_raffle_code_by_id(new_code.unique_id).unwrap();
-    assert_eq!(usage_count, None);
-    
-
-    for i in 10..20 {
-        db.register_partecipant(i, Some(2)).unwrap();
+fn test_sqlite_conformance() {
+    conformance::run_all(|| SQLiteInstance::create(":memory:").unwrap());
+}
+
+#[test]
+fn test_memory_conformance() {
+    conformance::run_all(MemoryInstance::create);
+}
+
+// A database file of its own under the temp dir, for the tests that need to reopen it
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(name: &str) -> TempFile {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let file = format!("userdb_{}_{}_{}.db", name, std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        TempFile(std::env::temp_dir().join(file))
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn make_partecipant(user_id: UserID, priority: usize) -> Partecipant {
+    Partecipant {
+        user_id,
+        joined_when: 0,
+        priority
+    }
+}
+
+#[test]
+fn test_weighted_draw() {
+    let partecipants = vec![make_partecipant(0, 9), make_partecipant(1, 1), make_partecipant(2, 0)];
+    let mut rng = StdRng::seed_from_u64(42);
+
+    // Winners are drawn without replacement and whoever has no tickets never wins
+    let winners = draw_winners(partecipants.clone(), 3, DrawMode::Weighted, &mut rng);
+    assert_eq!(winners.len(), 2);
+    assert!(winners.iter().all(|w| w.user_id != 2));
+    assert_ne!(winners[0].user_id, winners[1].user_id);
+
+    // The same seed gives the same winners, whatever order the partecipants are in
+    let mut reversed = partecipants.clone();
+    reversed.reverse();
+    let first = draw_winners(partecipants.clone(), 1, DrawMode::Weighted, &mut StdRng::seed_from_u64(7));
+    let second = draw_winners(reversed, 1, DrawMode::Weighted, &mut StdRng::seed_from_u64(7));
+    assert_eq!(first[0].user_id, second[0].user_id);
+
+    // Each point is one ticket, so user 0 should win about 90% of the draws
+    let wins = (0..2000)
+        .filter(|_| draw_winners(partecipants.clone(), 1, DrawMode::Weighted, &mut rng)[0].user_id == 0)
+        .count();
+    assert!(wins > 1700 && wins < 1900, "user 0 won {} draws out of 2000", wins);
+}
+
+#[test]
+fn test_leaderboard_draw() {
+    let partecipants = vec![make_partecipant(0, 1), make_partecipant(1, 5), make_partecipant(2, 3)];
+    let winners = draw_winners(partecipants, 2, DrawMode::Leaderboard, &mut StdRng::seed_from_u64(0));
+    assert_eq!(winners.iter().map(|w| w.user_id).collect::<Vec<_>>(), vec![1, 2]);
+}
+
+#[test]
+fn test_seeded_stop_raffle() {
+    let run_raffle = || {
+        let mut db = SQLiteInstance::create_with_seed(":memory:", 1234).unwrap();
+        let raffle = start_raffle(&mut db, "Seeded raffle", DrawMode::Weighted);
+        for i in 0..20 {
+            db.register_partecipant(raffle, i, if i > 10 { Some(i % 3) } else { None }, None).unwrap();
+        }
+        let winners = db.stop_raffle(raffle, 5).unwrap().winners;
+        db.close().unwrap();
+        winners.into_iter().map(|w| w.user_id).collect::<Vec<_>>()
+    };
+    let winners = run_raffle();
+    assert_eq!(winners.len(), 5);
+    assert_eq!(winners, run_raffle());
+}
+
+#[test]
+fn test_commit_reveal_draw() {
+    let mut db = SQLiteInstance::create(":memory:").unwrap();
+    let raffle = start_raffle(&mut db, "Fair raffle", DrawMode::Weighted);
+    let raffle = db.get_raffle(raffle).unwrap().unwrap();
+    let published_hash = raffle.seed_hash.unwrap();
+    for i in 0..30 {
+        db.register_partecipant(raffle.raffle_id, i, if i % 2 == 0 { Some(i / 2) } else { None }, None).unwrap();
     }
-    for i in 21..30 {
-        db.register_partecipant(i, None).unwrap();
+    let partecipants = Vec::from_iter(db.get_partecipants(raffle.raffle_id).unwrap().into_iter());
+    let outcome = db.stop_raffle(raffle.raffle_id, 3).unwrap();
+    db.close().unwrap();
+
+    // The revealed seed must match the published hash and reproduce the very same winners
+    assert_eq!(seed_hash(&outcome.seed), published_hash);
+    assert_eq!(partecipants_hash(&partecipants), outcome.partecipants_hash);
+    assert_eq!(seed_from_hex(&to_hex(&outcome.seed)), Some(outcome.seed));
+    let reproduced = draw_from_seed(partecipants, 3, DrawMode::Weighted, &outcome.seed);
+    assert_eq!(
+        reproduced.iter().map(|w| w.user_id).collect::<Vec<_>>(),
+        outcome.winners.iter().map(|w| w.user_id).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_raffle_history() {
+    let mut db = SQLiteInstance::create(":memory:").unwrap();
+    let first = start_raffle(&mut db, "First raffle", DrawMode::Leaderboard);
+    db.register_partecipant(first, 1, None, None).unwrap();
+    db.register_partecipant(first, 2, Some(1), None).unwrap();
+    let code = db.generate_raffle_code(first, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    db.redeem_code(2, code.unique_id).unwrap();
+    db.stop_raffle(first, 1).unwrap();
+
+    // Codes of an ended raffle can't be redeemed anymore
+    assert_eq!(db.get_raffle_code_by_name(&code.code).unwrap(), None);
+    assert!(db.generate_raffle_code(first, CodeUseCount::Once, CodeOptions::default()).is_err());
+
+    let second = start_raffle(&mut db, "Second raffle", DrawMode::Leaderboard);
+    db.register_partecipant(second, 2, None, None).unwrap();
+    assert_eq!(db.get_partecipant(second, 2).unwrap().unwrap().priority, 1);
+    assert_eq!(db.get_referrer_of_user(second, 2).unwrap(), None);
+
+    // Nothing of the first raffle was lost
+    assert_eq!(db.get_raffles().unwrap().len(), 2);
+    assert_eq!(db.get_partecipant(first, 2).unwrap().unwrap().priority, 2);
+    assert_eq!(db.get_referrals(first).unwrap(), vec![Referral { referrer: 1, referee: 2 }]);
+    let used_codes = db.get_used_codes(first).unwrap();
+    assert_eq!(used_codes.len(), 1);
+    assert_eq!(used_codes[0].partecpiant_user_id, 2);
+    assert_eq!(used_codes[0].code, code.code);
+    assert_eq!(db.get_raffle_winners(first).unwrap(), vec![1]);
+
+    db.close().unwrap();
+}
+
+#[test]
+fn test_concurrent_raffles() {
+    let mut db = SQLiteInstance::create(":memory:").unwrap();
+    let shoes = start_raffle(&mut db, "Shoes", DrawMode::Leaderboard);
+    let hats = start_raffle(&mut db, "Hats", DrawMode::Leaderboard);
+    assert!(!db.create_raffle("Shoes", "Test Description", DrawMode::Weighted, ScoringRules::default()).unwrap().is_success());
+    assert_eq!(db.get_ongoing_raffles().unwrap().len(), 2);
+
+    db.register_partecipant(shoes, 1, None, None).unwrap();
+    db.register_partecipant(shoes, 2, Some(1), None).unwrap();
+    db.register_partecipant(hats, 2, None, None).unwrap();
+    db.register_partecipant(hats, 3, Some(1), None).unwrap(); // 1 is not in this raffle, so no referral
+    assert_eq!(db.get_referees_of_user(shoes, 1).unwrap(), vec![2]);
+    assert_eq!(db.get_referrer_of_user(hats, 3).unwrap(), None);
+    assert!(!db.is_partecipant(hats, 1).unwrap());
+
+    // Codes only count for the raffle they were generated for
+    let hats_code = db.generate_raffle_code(hats, CodeUseCount::Illimited, CodeOptions::default()).unwrap();
+    assert_eq!(db.redeem_code(1, hats_code.unique_id).unwrap(), CodeRedeemalResult::NonExistingUser);
+    assert_eq!(db.redeem_code(2, hats_code.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    assert_eq!(db.get_partecipant(hats, 2).unwrap().unwrap().priority, 2);
+    assert_eq!(db.get_partecipant(shoes, 2).unwrap().unwrap().priority, 1);
+
+    // Ending a raffle leaves the others running
+    db.stop_raffle(shoes, 1).unwrap();
+    let ongoing = db.get_ongoing_raffles().unwrap();
+    assert_eq!(ongoing.len(), 1);
+    assert_eq!(ongoing[0].raffle_id, hats);
+    assert!(db.create_raffle("Shoes", "Test Description", DrawMode::Weighted, ScoringRules::default()).unwrap().is_success());
+
+    db.close().unwrap();
+}
+
+#[test]
+fn test_migrate_legacy_database() {
+    let temp = TempFile::new("migrations");
+    let file = temp.path();
+    {
+        // A database as written before migrations existed, with a raffle running
+        let connection = rusqlite::Connection::open(file).unwrap();
+        connection.execute_batch(MIGRATIONS[0].sql).unwrap();
+        connection.execute_batch("
+            INSERT INTO RAFFLE (raffle_name, raffle_message, started_when, ended_when) VALUES ('Old raffle', 'desc', 1, 2);
+            INSERT INTO RAFFLE (raffle_name, raffle_message, started_when) VALUES ('Ongoing raffle', 'desc', 3);
+            INSERT INTO PARTECIPANTS (user_id, joined_when) VALUES (1, 10), (2, 11);
+            INSERT INTO REFERRALS (referrer_id, referee_id) VALUES (1, 2);
+            INSERT INTO REDEEMABLE_CODES (code, remaining_uses, generated_when) VALUES ('OLDCODE1', 5, 12);
+            INSERT INTO USED_CODES (user_id, code_id, used_when) VALUES (1, 1, 13), (1, 1, 14);
+        ").unwrap();
     }
 
-    let winners = db.stop_raffle(3).unwrap();
-    println!("Winners: {:?}", winners);
-    assert_eq!(winners.into_iter().next().unwrap().user_id, 2);
+    let mut db = SQLiteInstance::create(file).unwrap();
+    let ongoing = db.get_ongoing_raffles().unwrap();
+    assert_eq!(ongoing.len(), 1);
+    assert_eq!(ongoing[0].raffle_name, "Ongoing raffle");
+    assert_eq!(ongoing[0].draw_mode, DrawMode::Leaderboard);
+    let raffle = ongoing[0].raffle_id;
+    assert_eq!(db.get_partecipants(raffle).unwrap().len(), 2);
+    assert_eq!(db.get_partecipant(raffle, 1).unwrap().unwrap().priority, 3);
+    let sources = Vec::from_iter(db.get_point_events(raffle, 1).unwrap().into_iter().map(|event| event.source));
+    assert_eq!(sources, vec![PointSource::Join, PointSource::Referral(2), PointSource::Code(1)]);
+    assert_eq!(db.get_referrer_of_user(raffle, 2).unwrap(), Some(1));
+    let code = db.get_raffle_code_by_name("OLDCODE1").unwrap().unwrap();
+    assert_eq!(code.raffle_id, raffle);
+    // New codes must not reuse the id of the migrated ones
+    assert!(db.generate_raffle_code(raffle, CodeUseCount::Once, CodeOptions::default()).unwrap().unique_id > code.unique_id);
+    db.close().unwrap();
 
+    // Opening it again must not run anything twice
+    let db = SQLiteInstance::create(file).unwrap();
     db.close().unwrap();
-}
\ No newline at end of file
+    let connection = rusqlite::Connection::open(file).unwrap();
+    assert_eq!(schema_version(&connection).unwrap(), latest_version());
+
+    // A database from a newer build must be refused
+    connection.execute_batch(&format!("PRAGMA user_version = {}", latest_version() + 1)).unwrap();
+    drop(connection);
+    let error = SQLiteInstance::create(file).err().unwrap();
+    assert!(matches!(error, RaffleDbError::Migration(MigrationError::DatabaseTooNew { .. })), "{}", error);
+    assert!(error.to_string().contains("schema version"), "{}", error);
+}
+
+#[test]
+fn test_memory_backend_draws_like_sqlite() {
+    // Codes and referral tokens come from the seed too
+    fn run_raffle<DB: RaffleDB>(mut db: DB) -> (Option<String>, Vec<String>, Vec<UserID>) {
+        let raffle = start_raffle(&mut db, "Seeded raffle", DrawMode::Weighted);
+        let raffle = db.get_raffle(raffle).unwrap().unwrap();
+        for i in 0..20 {
+            db.register_partecipant(raffle.raffle_id, i, if i > 10 { Some(i % 3) } else { None }, None).unwrap();
+        }
+        let mut generated = Vec::from_iter(db.generate_raffle_codes(raffle.raffle_id, 3, CodeUseCount::Once, CodeOptions::default()).unwrap()
+            .into_iter()
+            .map(|code| code.code));
+        generated.push(db.get_referral_token(raffle.raffle_id, 1).unwrap().unwrap().token);
+        let winners = db.stop_raffle(raffle.raffle_id, 5).unwrap().winners;
+        (raffle.seed_hash, generated, winners.into_iter().map(|w| w.user_id).collect())
+    }
+    let from_sqlite = run_raffle(SQLiteInstance::create_with_seed(":memory:", 1234).unwrap());
+    assert_eq!(run_raffle(SQLiteInstance::create_with_seed(":memory:", 1234).unwrap()), from_sqlite);
+    assert_eq!(run_raffle(MemoryInstance::create_with_seed(1234)), from_sqlite);
+}
+
+#[test]
+fn test_redeem_race_between_connections() {
+    let temp = TempFile::new("redeem_race");
+    let file = temp.path();
+    let mut first = SQLiteInstance::create(file).unwrap();
+    let mut second = SQLiteInstance::create(file).unwrap();
+    let raffle = start_raffle(&mut first, "Race", DrawMode::Weighted);
+    first.register_partecipant(raffle, 1, None, None).unwrap();
+    first.register_partecipant(raffle, 2, None, None).unwrap();
+    let code = first.generate_raffle_code(raffle, CodeUseCount::Once, CodeOptions::default()).unwrap();
+
+    // Both saw the code with one use left, only one of them can have it
+    assert!(second.get_raffle_code_by_id(code.unique_id).unwrap().is_some());
+    assert_eq!(first.redeem_code(1, code.unique_id).unwrap(), CodeRedeemalResult::Redeemed(1));
+    assert_eq!(second.redeem_code(2, code.unique_id).unwrap(), CodeRedeemalResult::Exhausted);
+    assert_eq!(second.get_used_codes(raffle).unwrap().len(), 1);
+    assert_eq!(second.get_partecipant(raffle, 2).unwrap().unwrap().priority, 1);
+    first.close().unwrap();
+    second.close().unwrap();
+
+    // The schema itself refuses a second redeem of the same code
+    let connection = rusqlite::Connection::open(file).unwrap();
+    let duplicate = connection.execute(
+        "INSERT INTO USED_CODES (raffle_id, user_id, code_id, used_when) VALUES (?1, 1, ?2, 0)",
+        rusqlite::params!(raffle, code.unique_id));
+    assert!(duplicate.is_err());
+}
+
+#[test]
+fn test_code_check_character() {
+    let format = CodeFormat { check_character: true, prefix: "RF".to_owned(), ..CodeFormat::default() }.validated().unwrap();
+    let mut rng = StdRng::seed_from_u64(16);
+    for _ in 0..200 {
+        let code = format.generate(&mut rng);
+        assert!(!format.looks_mistyped(&code));
+        assert!(!format.looks_mistyped(&code.to_lowercase()));
+        // Luhn mod N catches any single wrong character
+        let mut chars: Vec<char> = code.chars().collect();
+        let position = 2 + rng.gen_range(0..chars.len() - 2);
+        chars[position] = READABLE_ALPHABET.chars().find(|c| *c != chars[position]).unwrap();
+        assert!(format.looks_mistyped(&chars.iter().collect::<String>()));
+    }
+    // Codes of another format can't be judged
+    assert!(!format.looks_mistyped("SOMETHINGELSE"));
+    assert!(!CodeFormat::default().looks_mistyped("ABCDEFGH"));
+}